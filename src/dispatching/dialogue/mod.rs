@@ -171,4 +171,7 @@ pub use storage::{RedisStorage, RedisStorageError};
 #[cfg(feature = "sqlite-storage")]
 pub use storage::{SqliteStorage, SqliteStorageError};
 
-pub use storage::{serializer, InMemStorage, InMemStorageError, Serializer, Storage, TraceStorage};
+pub use storage::{
+    serializer, ChatUserKey, EnumerableStorage, InMemStorage, InMemStorageError, ParseKeyError,
+    Serializer, Storage, TraceStorage, TtlStorage, UserKey,
+};
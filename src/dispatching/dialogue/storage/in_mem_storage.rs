@@ -1,6 +1,14 @@
-use super::Storage;
-use futures::future::BoxFuture;
-use std::{collections::HashMap, sync::Arc};
+use super::{EnumerableStorage, Storage, EXPIRED_DIALOGUE_RETENTION, SWEEP_INTERVAL};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt},
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use tokio::sync::Mutex;
 
@@ -18,56 +26,249 @@ pub enum InMemStorageError {
 /// All your dialogues will be lost after you restart your bot. If you need to
 /// store them somewhere on a drive, you should use e.g.
 /// [`super::SqliteStorage`] or implement your own.
+///
+/// Expired dialogues are kept for a day, so that [`Storage::take_expired`] can
+/// report them, and then removed on one of the next updates.
+#[derive(Debug)]
+pub struct InMemStorage<D, K = i64> {
+    map: Mutex<HashMap<K, Entry<D>>>,
+    /// When expired dialogues have been removed from `map` for the last time.
+    swept_at: std::sync::Mutex<Instant>,
+}
+
 #[derive(Debug)]
-pub struct InMemStorage<D> {
-    map: Mutex<HashMap<i64, D>>,
+struct Entry<D> {
+    dialogue: D,
+    expires_at: Option<Instant>,
 }
 
-impl<S> InMemStorage<S> {
+impl<D> Entry<D> {
+    fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= Instant::now())
+    }
+
+    /// Returns `true` if the dialogue has expired so long ago that it doesn't
+    /// need to be kept anymore.
+    fn is_stale(&self, now: Instant) -> bool {
+        self.expires_at
+            .and_then(|expires_at| expires_at.checked_add(EXPIRED_DIALOGUE_RETENTION))
+            .map_or(false, |stale_at| stale_at <= now)
+    }
+}
+
+impl<S, K> InMemStorage<S, K> {
     #[must_use]
     pub fn new() -> Arc<Self> {
-        Arc::new(Self { map: Mutex::new(HashMap::new()) })
+        Arc::new(Self {
+            map: Mutex::new(HashMap::new()),
+            swept_at: std::sync::Mutex::new(Instant::now()),
+        })
     }
 }
 
-impl<D> Storage<D> for InMemStorage<D>
+impl<D, K> InMemStorage<D, K>
+where
+    K: Hash + Eq,
+{
+    /// Inserts `entry` into `map`, removing stale dialogues once in
+    /// [`SWEEP_INTERVAL`].
+    fn insert(&self, map: &mut HashMap<K, Entry<D>>, key: K, entry: Entry<D>) {
+        let now = Instant::now();
+        let mut swept_at = self.swept_at.lock().unwrap();
+
+        if now.saturating_duration_since(*swept_at) >= SWEEP_INTERVAL {
+            map.retain(|_, entry| !entry.is_stale(now));
+            *swept_at = now;
+        }
+
+        map.insert(key, entry);
+    }
+}
+
+impl<D, K> Storage<D, K> for InMemStorage<D, K>
 where
     D: Clone,
     D: Send + 'static,
+    K: Hash + Eq + Send + 'static,
 {
     type Error = InMemStorageError;
 
-    fn remove_dialogue(self: Arc<Self>, chat_id: i64) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(async move {
-            self.map
+            // An expired dialogue is the same as a missing one.
+            match self.map.lock().await.remove(&key) {
+                Some(entry) if !entry.is_expired() => Ok(()),
+                _ => Err(InMemStorageError::DialogueNotFound),
+            }
+        })
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let mut map = self.map.lock().await;
+            self.insert(&mut map, key, Entry { dialogue, expires_at: None });
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            Ok(self
+                .map
                 .lock()
                 .await
-                .remove(&chat_id)
-                .map_or(Err(InMemStorageError::DialogueNotFound), |_| Ok(()))
+                .get(&key)
+                .filter(|entry| !entry.is_expired())
+                .map(|entry| entry.dialogue.clone()))
         })
     }
 
-    fn update_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        chat_id: i64,
+        key: K,
         dialogue: D,
+        ttl: Duration,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
         Box::pin(async move {
-            self.map.lock().await.insert(chat_id, dialogue);
+            let expires_at = Instant::now().checked_add(ttl);
+            let mut map = self.map.lock().await;
+            self.insert(&mut map, key, Entry { dialogue, expires_at });
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        Box::pin(async move {
+            let mut map = self.map.lock().await;
+
+            // Expired dialogues are kept until this function is called (or until they
+            // become stale), so it knows that the dialogue has expired.
+            let expired = map.get(&key).map_or(false, Entry::is_expired);
+            if expired {
+                map.remove(&key);
+            }
+
+            Ok(expired)
+        })
+    }
+
+    fn update_dialogue_with<F>(
+        self: Arc<Self>,
+        key: K,
+        ttl: Option<Duration>,
+        mut f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        Box::pin(async move {
+            // The lock is held while `f` is called, so no one can modify the dialogue
+            // meanwhile.
+            let mut map = self.map.lock().await;
+
+            let current = map
+                .get(&key)
+                .filter(|entry| !entry.is_expired())
+                .map(|entry| entry.dialogue.clone());
+            let dialogue = f(current);
+            let expires_at = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+
+            self.insert(&mut map, key, Entry { dialogue, expires_at });
+            Ok(())
+        })
+    }
+}
+
+impl<D, K> EnumerableStorage<D, K> for InMemStorage<D, K>
+where
+    D: Clone,
+    D: Send + 'static,
+    K: Clone + Hash + Eq + Send + 'static,
+{
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        stream::once(async move {
+            let dialogues: Vec<_> = self
+                .map
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired())
+                .map(|(key, entry)| Ok((key.clone(), entry.dialogue.clone())))
+                .collect();
+
+            stream::iter(dialogues)
+        })
+        .flatten()
+        .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>> {
+        Box::pin(async move {
+            Ok(self.map.lock().await.values().filter(|entry| !entry.is_expired()).count())
+        })
+    }
+
+    fn remove_dialogues_where<P>(
         self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
-        Box::pin(async move { Ok(self.map.lock().await.get(&chat_id).map(ToOwned::to_owned)) })
+        mut predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static,
+    {
+        Box::pin(async move {
+            let mut map = self.map.lock().await;
+            let len = map.len();
+
+            map.retain(|key, entry| entry.is_expired() || !predicate(key, &entry.dialogue));
+            Ok(len - map.len())
+        })
     }
 }
+
+#[tokio::test]
+async fn in_mem_storage_enumerates_dialogues() {
+    let storage = InMemStorage::<&str>::new();
+    Arc::clone(&storage).update_dialogue(1, "a").await.unwrap();
+    Arc::clone(&storage).update_dialogue(2, "b").await.unwrap();
+    Arc::clone(&storage).update_dialogue(3, "b").await.unwrap();
+
+    let mut dialogues: Vec<_> =
+        Arc::clone(&storage).dialogues().map(Result::unwrap).collect().await;
+    dialogues.sort_unstable();
+    assert_eq!(dialogues, [(1, "a"), (2, "b"), (3, "b")]);
+
+    let removed = Arc::clone(&storage).remove_dialogues_where(|_, d| *d == "b").await.unwrap();
+    assert_eq!(removed, 2);
+    assert_eq!(Arc::clone(&storage).count_dialogues().await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn in_mem_storage_does_not_remove_expired_dialogues() {
+    let storage = InMemStorage::<&str>::new();
+    Arc::clone(&storage).update_dialogue_with_ttl(1, "a", Duration::ZERO).await.unwrap();
+
+    assert!(matches!(
+        Arc::clone(&storage).remove_dialogue(1).await,
+        Err(InMemStorageError::DialogueNotFound)
+    ));
+}
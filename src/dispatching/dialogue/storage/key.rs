@@ -0,0 +1,90 @@
+use std::{
+    fmt::{self, Display},
+    num::ParseIntError,
+    str::FromStr,
+};
+
+/// A key of a dialogue with a particular user in a particular chat.
+///
+/// Use it to have a separate dialogue with each member of a group chat.
+///
+/// It is displayed as `<chat_id>:<user_id>`, which is how it is stored by
+/// [`RedisStorage`] and [`SqliteStorage`].
+///
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChatUserKey {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+impl ChatUserKey {
+    #[must_use]
+    pub fn new(chat_id: i64, user_id: i64) -> Self {
+        Self { chat_id, user_id }
+    }
+}
+
+impl Display for ChatUserKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.chat_id, self.user_id)
+    }
+}
+
+impl FromStr for ChatUserKey {
+    type Err = ParseKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (chat_id, user_id) = s.split_once(':').ok_or(ParseKeyError::InvalidFormat)?;
+        Ok(Self { chat_id: chat_id.parse()?, user_id: user_id.parse()? })
+    }
+}
+
+/// A key of a dialogue with a particular user, regardless of a chat.
+///
+/// It is displayed as `user:<user_id>`, which is how it is stored by
+/// [`RedisStorage`] and [`SqliteStorage`].
+///
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UserKey(pub i64);
+
+impl Display for UserKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "user:{}", self.0)
+    }
+}
+
+impl FromStr for UserKey {
+    type Err = ParseKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let user_id = s.strip_prefix("user:").ok_or(ParseKeyError::InvalidFormat)?;
+        Ok(Self(user_id.parse()?))
+    }
+}
+
+/// An error returned from parsing [`ChatUserKey`] and [`UserKey`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseKeyError {
+    #[error("invalid dialogue key format")]
+    InvalidFormat,
+
+    #[error("invalid ID in a dialogue key: {0}")]
+    InvalidId(#[from] ParseIntError),
+}
+
+#[test]
+fn keys_roundtrip() {
+    let key = ChatUserKey::new(-100, 42);
+    assert_eq!(key.to_string(), "-100:42");
+    assert_eq!("-100:42".parse(), Ok(key));
+
+    assert_eq!(UserKey(42).to_string(), "user:42");
+    assert_eq!("user:42".parse(), Ok(UserKey(42)));
+
+    assert_eq!("42".parse::<ChatUserKey>(), Err(ParseKeyError::InvalidFormat));
+    assert!(matches!("user:a".parse::<UserKey>(), Err(ParseKeyError::InvalidId(_))));
+}
@@ -1,7 +1,9 @@
 pub mod serializer;
 
 mod in_mem_storage;
+mod key;
 mod trace_storage;
+mod ttl_storage;
 
 #[cfg(feature = "redis-storage")]
 mod redis_storage;
@@ -9,22 +11,38 @@ mod redis_storage;
 #[cfg(feature = "sqlite-storage")]
 mod sqlite_storage;
 
-use futures::future::BoxFuture;
+use futures::{
+    future::{ready, BoxFuture},
+    stream::BoxStream,
+};
 
 pub use self::{
     in_mem_storage::{InMemStorage, InMemStorageError},
+    key::{ChatUserKey, ParseKeyError, UserKey},
     trace_storage::TraceStorage,
+    ttl_storage::TtlStorage,
 };
 
 #[cfg(feature = "redis-storage")]
 #[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "redis-storage")))]
 pub use redis_storage::{RedisStorage, RedisStorageError};
 pub use serializer::Serializer;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 #[cfg(feature = "sqlite-storage")]
 pub use sqlite_storage::{SqliteStorage, SqliteStorageError};
 
+/// How long storages remember that a dialogue has expired, so that
+/// [`Storage::take_expired`] can report it.
+///
+/// After this period, expired dialogues are removed for good to free memory.
+const EXPIRED_DIALOGUE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often [`InMemStorage`] and [`SqliteStorage`] look for dialogues which
+/// have expired more than [`EXPIRED_DIALOGUE_RETENTION`] ago, since it takes a
+/// pass over all the dialogues.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
 /// A storage of dialogues.
 ///
 /// You can implement this trait for a structure that communicates with a DB and
@@ -33,44 +51,168 @@ pub use sqlite_storage::{SqliteStorage, SqliteStorageError};
 /// `Storage` is used only to store dialogue states, i.e. it can't be used as a
 /// generic database.
 ///
+/// Dialogues are indexed by keys of type `K`, which is a chat ID by default.
+/// Use other keys, e.g. [`ChatUserKey`], to have a separate dialogue with each
+/// member of a group chat. The storages provided by teloxide accept keys of
+/// any type; [`RedisStorage`] and [`SqliteStorage`] store them as strings, so
+/// they require `K: Display`.
+///
 /// Currently we support the following storages out of the box:
 ///
 /// - [`InMemStorage`] -- a storage based on [`std::collections::HashMap`].
 /// - [`RedisStorage`] -- a Redis-based storage.
 /// - [`SqliteStorage`] -- an SQLite-based persistent storage.
 ///
+/// [`ChatUserKey`]: crate::dispatching::dialogue::ChatUserKey
 /// [`InMemStorage`]: crate::dispatching::dialogue::InMemStorage
 /// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
 /// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
-pub trait Storage<D> {
+pub trait Storage<D, K = i64> {
     type Error;
 
-    /// Removes a dialogue indexed by `chat_id`.
+    /// Removes a dialogue indexed by `key`.
     ///
-    /// If the dialogue indexed by `chat_id` does not exist, this function
-    /// results in an error.
+    /// If the dialogue indexed by `key` does not exist, this function results
+    /// in an error.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static;
 
-    /// Updates a dialogue indexed by `chat_id` with `dialogue`.
+    /// Updates a dialogue indexed by `key` with `dialogue`.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: i64,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static;
 
-    /// Returns the dialogue indexed by `chat_id`.
+    /// Returns the dialogue indexed by `key`.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>>;
+
+    /// Updates a dialogue indexed by `key` with `dialogue`, which expires
+    /// after `ttl`.
+    ///
+    /// Once the dialogue has expired, [`Storage::get_dialogue`] returns `None`
+    /// and [`Storage::take_expired`] returns `true`.
+    ///
+    /// The default implementation ignores `ttl`, i.e. the dialogue never
+    /// expires. All the storages provided by teloxide support expiration.
     #[must_use = "Futures are lazy and do nothing unless polled with .await"]
-    fn get_dialogue(
+    fn update_dialogue_with_ttl(
         self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>>;
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        let _ = ttl;
+        <Self as Storage<D, K>>::update_dialogue(self, key, dialogue)
+    }
+
+    /// Returns `true` if a dialogue indexed by `key` has expired, and forgets
+    /// about it, so the next call returns `false`.
+    ///
+    /// Updating or removing the dialogue also forgets that it has expired. A
+    /// storage may also forget it some time after the dialogue has expired, to
+    /// free memory.
+    ///
+    /// The default implementation always returns `false`.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        let _ = key;
+        Box::pin(ready(Ok(false)))
+    }
+
+    /// Updates a dialogue indexed by `key` with the result of `f`, which is
+    /// called with the current dialogue (`None` if there is no dialogue).
+    ///
+    /// Unlike [`Storage::get_dialogue`] followed by
+    /// [`Storage::update_dialogue`], the update is atomic, i.e. concurrent
+    /// updates of the same dialogue don't overwrite each other. `f` may be
+    /// called more than once if the dialogue has been modified concurrently,
+    /// so it shouldn't have side effects.
+    ///
+    /// If `ttl` is `Some`, the dialogue expires after it, as with
+    /// [`Storage::update_dialogue_with_ttl`].
+    ///
+    /// The default implementation is **not** atomic: it just gets the dialogue
+    /// and then updates it. All the storages provided by teloxide override it.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn update_dialogue_with<F>(
+        self: Arc<Self>,
+        key: K,
+        ttl: Option<Duration>,
+        mut f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        Box::pin(async move {
+            let current = <Self as Storage<D, K>>::get_dialogue(Arc::clone(&self), key.clone());
+            let dialogue = f(current.await?);
+
+            match ttl {
+                Some(ttl) => {
+                    <Self as Storage<D, K>>::update_dialogue_with_ttl(self, key, dialogue, ttl)
+                        .await
+                }
+                None => <Self as Storage<D, K>>::update_dialogue(self, key, dialogue).await,
+            }
+        })
+    }
+}
+
+/// A [`Storage`] which can enumerate and remove all the stored dialogues, e.g.
+/// for administrative tools.
+///
+/// Expired dialogues (see [`Storage::update_dialogue_with_ttl`]) are skipped.
+/// If dialogues of different types are stored together (e.g. with an offset
+/// store of a listener), only the dialogues with keys of type `K` which can be
+/// deserialized as `D` are taken into account.
+///
+/// The storages provided by teloxide implement this trait; [`RedisStorage`]
+/// and [`SqliteStorage`] parse keys from strings, so they require `K: FromStr`.
+/// [`RedisStorage`] scans all the keys in a database, so it should use a
+/// database dedicated to dialogues.
+///
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+pub trait EnumerableStorage<D, K = i64>: Storage<D, K> {
+    /// Returns all the dialogues along with their keys.
+    ///
+    /// Dialogues are fetched lazily in batches, so the stream may miss or
+    /// return outdated dialogues which are modified concurrently. The stream
+    /// returned by [`RedisStorage`] may also return a dialogue more than once.
+    #[must_use = "Streams are lazy and do nothing unless polled"]
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>>;
+
+    /// Returns the number of the dialogues.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>>;
+
+    /// Removes all the dialogues for which `predicate` returns `true`, and
+    /// returns the number of removed dialogues.
+    ///
+    /// A dialogue which is modified after `predicate` has been called for it
+    /// is not removed.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn remove_dialogues_where<P>(
+        self: Arc<Self>,
+        predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static;
 }
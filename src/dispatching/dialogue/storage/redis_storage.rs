@@ -1,12 +1,18 @@
-use super::{serializer::Serializer, Storage};
-use futures::future::BoxFuture;
+use super::{serializer::Serializer, EnumerableStorage, Storage, EXPIRED_DIALOGUE_RETENTION};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt},
+};
 use redis::{AsyncCommands, IntoConnectionInfo};
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    convert::Infallible,
+    collections::VecDeque,
+    convert::{Infallible, TryFrom},
     fmt::{Debug, Display},
     ops::DerefMut,
+    str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::sync::Mutex;
@@ -29,7 +35,21 @@ where
 }
 
 /// A dialogue storage based on [Redis](https://redis.io/).
+///
+/// Dialogues are stored under keys displayed as strings, e.g. `123` for a chat
+/// ID or `123:456` for [`ChatUserKey`].
+///
+/// Dialogues updated with a TTL are removed by Redis when they expire, while
+/// Redis remembers that they have expired for a day, so that
+/// [`Storage::take_expired`] can report it.
+///
+/// [`Storage::update_dialogue_with`] opens a new connection for every
+/// transaction, since `WATCH` affects the whole connection, while other
+/// methods share a single connection.
+///
+/// [`ChatUserKey`]: crate::dispatching::dialogue::ChatUserKey
 pub struct RedisStorage<S> {
+    client: redis::Client,
     conn: Mutex<redis::aio::Connection>,
     serializer: S,
 }
@@ -39,29 +59,30 @@ impl<S> RedisStorage<S> {
         url: impl IntoConnectionInfo,
         serializer: S,
     ) -> Result<Arc<Self>, RedisStorageError<Infallible>> {
-        Ok(Arc::new(Self {
-            conn: Mutex::new(redis::Client::open(url)?.get_async_connection().await?),
-            serializer,
-        }))
+        let client = redis::Client::open(url)?;
+        let conn = Mutex::new(client.get_async_connection().await?);
+
+        Ok(Arc::new(Self { client, conn, serializer }))
     }
 }
 
-impl<S, D> Storage<D> for RedisStorage<S>
+impl<S, D, K> Storage<D, K> for RedisStorage<S>
 where
     S: Send + Sync + Serializer<D> + 'static,
     D: Send + Serialize + DeserializeOwned + 'static,
+    K: Display + Send + 'static,
     <S as Serializer<D>>::Error: Debug + Display,
 {
     type Error = RedisStorageError<<S as Serializer<D>>::Error>;
 
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>> {
         Box::pin(async move {
+            let key = key.to_string();
             let deleted_rows_count = redis::pipe()
                 .atomic()
-                .del(chat_id)
+                .del(&key)
+                .del(expiry_marker(&key))
+                .ignore()
                 .query_async::<_, redis::Value>(self.conn.lock().await.deref_mut())
                 .await?;
 
@@ -82,29 +103,331 @@ where
 
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: i64,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>> {
         Box::pin(async move {
+            let key = key.to_string();
             let dialogue =
                 self.serializer.serialize(&dialogue).map_err(RedisStorageError::SerdeError)?;
-            self.conn.lock().await.set::<_, Vec<u8>, _>(chat_id, dialogue).await?;
+            let mut conn = self.conn.lock().await;
+
+            // The marker exists only if the dialogue has been updated with a TTL, so a
+            // transaction is usually not needed.
+            if conn.exists::<_, bool>(expiry_marker(&key)).await? {
+                set_dialogue(redis::pipe().atomic(), &key, dialogue, None)
+                    .query_async::<_, ()>(conn.deref_mut())
+                    .await?;
+            } else {
+                conn.set::<_, _, ()>(&key, dialogue).await?;
+            }
+
             Ok(())
         })
     }
 
-    fn get_dialogue(
-        self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
         Box::pin(async move {
             self.conn
                 .lock()
                 .await
-                .get::<_, Option<Vec<u8>>>(chat_id)
+                .get::<_, Option<Vec<u8>>>(key.to_string())
                 .await?
                 .map(|d| self.serializer.deserialize(&d).map_err(RedisStorageError::SerdeError))
                 .transpose()
         })
     }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let key = key.to_string();
+            let dialogue =
+                self.serializer.serialize(&dialogue).map_err(RedisStorageError::SerdeError)?;
+            set_dialogue(redis::pipe().atomic(), &key, dialogue, Some(ttl))
+                .query_async::<_, ()>(self.conn.lock().await.deref_mut())
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        Box::pin(async move {
+            let key = key.to_string();
+            let mut conn = self.conn.lock().await;
+
+            if conn.exists::<_, bool>(&key).await? {
+                return Ok(false);
+            }
+
+            let deleted_markers_count = conn.del::<_, i64>(expiry_marker(&key)).await?;
+            Ok(deleted_markers_count > 0)
+        })
+    }
+
+    fn update_dialogue_with<F>(
+        self: Arc<Self>,
+        key: K,
+        ttl: Option<Duration>,
+        mut f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        Box::pin(async move {
+            let key = key.to_string();
+            // A dedicated connection, which is closed on every exit path (including a panic
+            // of `f` and cancellation of this future), so the shared one is
+            // never left `WATCH`ing and other operations don't wait for the
+            // transaction.
+            let mut conn = self.client.get_async_connection().await?;
+
+            // An optimistic transaction: `EXEC` fails if the dialogue has been modified
+            // since `WATCH`, and then we try again.
+            loop {
+                redis::cmd("WATCH").arg(&key).query_async::<_, ()>(&mut conn).await?;
+
+                let current = conn.get::<_, Option<Vec<u8>>>(&key).await?;
+                let dialogue = current
+                    .map(|d| self.serializer.deserialize(&d))
+                    .transpose()
+                    .and_then(|current| self.serializer.serialize(&f(current)))
+                    .map_err(RedisStorageError::SerdeError)?;
+
+                let committed = set_dialogue(redis::pipe().atomic(), &key, dialogue, ttl)
+                    .query_async::<_, Option<()>>(&mut conn)
+                    .await?
+                    .is_some();
+
+                if committed {
+                    return Ok(());
+                }
+
+                log::trace!("A dialogue #{} has been modified concurrently, retrying", key);
+            }
+        })
+    }
+}
+
+/// Adds commands which set a dialogue indexed by `key` to `pipe`.
+fn set_dialogue<'a>(
+    pipe: &'a mut redis::Pipeline,
+    key: &str,
+    dialogue: Vec<u8>,
+    ttl: Option<Duration>,
+) -> &'a mut redis::Pipeline {
+    match ttl {
+        // The dialogue is removed by Redis when it expires, so the marker outlives it to tell
+        // an expired dialogue from a missing one.
+        Some(ttl) => {
+            let marker_ttl = ttl.saturating_add(EXPIRED_DIALOGUE_RETENTION);
+            pipe.pset_ex(key, dialogue, millis(ttl)).ignore();
+            pipe.pset_ex(expiry_marker(key), 1, millis(marker_ttl)).ignore()
+        }
+        None => pipe.set(key, dialogue).ignore().del(expiry_marker(key)).ignore(),
+    }
+}
+
+/// Converts `duration` to milliseconds for `PSETEX`, which requires a positive
+/// number.
+fn millis(duration: Duration) -> usize {
+    usize::try_from(duration.as_millis()).unwrap_or(usize::MAX).max(1)
+}
+
+/// A prefix of keys of markers which exist while dialogues have a TTL.
+const EXPIRY_MARKER_PREFIX: &str = "teloxide_dialogue_ttl:";
+
+/// Returns the key of a marker which exists while a dialogue indexed by `key`
+/// has a TTL, and for [`EXPIRED_DIALOGUE_RETENTION`] after the dialogue has
+/// expired.
+fn expiry_marker(key: &str) -> String {
+    format!("{}{}", EXPIRY_MARKER_PREFIX, key)
+}
+
+/// The number of keys which are scanned at once by [`EnumerableStorage`]
+/// methods.
+const SCAN_COUNT: usize = 100;
+
+/// Removes a dialogue (`KEYS[1]`) and its expiry marker (`KEYS[2]`) only if the
+/// dialogue is still the same (`ARGV[1]`).
+const REMOVE_IF_UNCHANGED: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[2])
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+impl<S> RedisStorage<S> {
+    /// Scans a batch of keys starting from `cursor`.
+    ///
+    /// Returns the next cursor (`0` if the scan has finished) and the
+    /// dialogues found in the batch, along with their keys (both raw and
+    /// parsed). Keys which can't be parsed as `K` are skipped.
+    async fn scan_dialogues<K>(
+        &self,
+        cursor: u64,
+    ) -> Result<(u64, Vec<(String, K, Vec<u8>)>), redis::RedisError>
+    where
+        K: FromStr,
+    {
+        let mut conn = self.conn.lock().await;
+
+        let (cursor, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(SCAN_COUNT)
+            .query_async::<_, (u64, Vec<Vec<u8>>)>(conn.deref_mut())
+            .await?;
+
+        let keys: Vec<(String, K)> = keys
+            .into_iter()
+            .filter_map(|key| String::from_utf8(key).ok())
+            .filter(|key| !key.starts_with(EXPIRY_MARKER_PREFIX))
+            .filter_map(|key| {
+                let parsed = key.parse().ok()?;
+                Some((key, parsed))
+            })
+            .collect();
+
+        if keys.is_empty() {
+            return Ok((cursor, Vec::new()));
+        }
+
+        // `MGET` returns `nil` for keys which don't hold strings, e.g. streams.
+        let values = redis::cmd("MGET")
+            .arg(keys.iter().map(|(key, _)| key).collect::<Vec<_>>())
+            .query_async::<_, Vec<Option<Vec<u8>>>>(conn.deref_mut())
+            .await?;
+
+        let dialogues = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|((key, parsed), value)| Some((key, parsed, value?)))
+            .collect();
+
+        Ok((cursor, dialogues))
+    }
+
+    /// Deserializes a dialogue found by [`RedisStorage::scan_dialogues`].
+    ///
+    /// Returns `None` if the dialogue can't be deserialized as `D`, e.g. if it
+    /// has been stored by a storage of another type which shares the
+    /// database.
+    fn deserialize<D>(&self, key: &str, dialogue: &[u8]) -> Option<D>
+    where
+        S: Serializer<D>,
+        <S as Serializer<D>>::Error: Display,
+    {
+        match self.serializer.deserialize(dialogue) {
+            Ok(dialogue) => Some(dialogue),
+            Err(err) => {
+                log::debug!("Skipping a dialogue #{} of another type: {}", key, err);
+                None
+            }
+        }
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for RedisStorage<S>
+where
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    K: Display + FromStr + Send + 'static,
+    <S as Serializer<D>>::Error: Debug + Display + Send + 'static,
+{
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let state = (self, Some(0), VecDeque::new());
+
+        stream::unfold(state, |(this, mut cursor, mut buffer)| async move {
+            loop {
+                if let Some(dialogue) = buffer.pop_front() {
+                    return Some((dialogue, (this, cursor, buffer)));
+                }
+
+                match this.scan_dialogues::<K>(cursor?).await {
+                    Ok((next, dialogues)) => {
+                        cursor = if next == 0 { None } else { Some(next) };
+                        buffer.extend(dialogues.into_iter().filter_map(|(raw_key, key, d)| {
+                            Some(Ok((key, this.deserialize(&raw_key, &d)?)))
+                        }));
+                    }
+                    Err(err) => return Some((Err(err.into()), (this, None, buffer))),
+                }
+            }
+        })
+        .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>> {
+        Box::pin(async move {
+            let mut cursor = 0;
+            let mut count = 0;
+
+            loop {
+                // Dialogues of other types have to be skipped, so the dialogues are
+                // deserialized instead of just counting keys.
+                let (next, dialogues) = self.scan_dialogues::<K>(cursor).await?;
+                count += dialogues
+                    .iter()
+                    .filter(|(raw_key, _, d)| self.deserialize::<D>(raw_key, d).is_some())
+                    .count();
+
+                if next == 0 {
+                    return Ok(count);
+                }
+                cursor = next;
+            }
+        })
+    }
+
+    fn remove_dialogues_where<P>(
+        self: Arc<Self>,
+        mut predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static,
+    {
+        Box::pin(async move {
+            let script = redis::Script::new(REMOVE_IF_UNCHANGED);
+            let mut cursor = 0;
+            let mut removed = 0;
+
+            loop {
+                let (next, dialogues) = self.scan_dialogues::<K>(cursor).await?;
+
+                for (raw_key, key, d) in dialogues {
+                    let dialogue = match self.deserialize::<D>(&raw_key, &d) {
+                        Some(dialogue) => dialogue,
+                        None => continue,
+                    };
+
+                    if predicate(&key, &dialogue) {
+                        removed += script
+                            .key(&raw_key)
+                            .key(expiry_marker(&raw_key))
+                            .arg(d)
+                            .invoke_async::<_, usize>(self.conn.lock().await.deref_mut())
+                            .await?;
+                    }
+                }
+
+                if next == 0 {
+                    return Ok(removed);
+                }
+                cursor = next;
+            }
+        })
+    }
 }
@@ -1,19 +1,38 @@
-use super::{serializer::Serializer, Storage};
-use futures::future::BoxFuture;
+use super::{
+    serializer::Serializer, EnumerableStorage, Storage, EXPIRED_DIALOGUE_RETENTION, SWEEP_INTERVAL,
+};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream, StreamExt},
+};
 use serde::{de::DeserializeOwned, Serialize};
 use sqlx::{sqlite::SqlitePool, Executor};
 use std::{
-    convert::Infallible,
+    collections::VecDeque,
+    convert::{Infallible, TryFrom},
     fmt::{Debug, Display},
-    str,
-    sync::Arc,
+    str::{self, FromStr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
 /// A persistent dialogue storage based on [SQLite](https://www.sqlite.org/).
+///
+/// Dialogues are stored under keys displayed as strings, e.g. `123` for a chat
+/// ID or `123:456` for [`ChatUserKey`], in the `chat_id` column of type `TEXT`.
+/// Tables created by older versions of teloxide, where the column is an
+/// integer, are migrated when the storage is opened.
+///
+/// Expired dialogues are kept for a day, so that [`Storage::take_expired`] can
+/// report them, and then removed on one of the next updates.
+///
+/// [`ChatUserKey`]: crate::dispatching::dialogue::ChatUserKey
 pub struct SqliteStorage<S> {
     pool: SqlitePool,
     serializer: S,
+    /// When expired dialogues have been removed for the last time.
+    swept_at: Mutex<Instant>,
 }
 
 /// An error returned from [`SqliteStorage`].
@@ -43,38 +62,72 @@ impl<S> SqliteStorage<S> {
         sqlx::query(
             r#"
 CREATE TABLE IF NOT EXISTS teloxide_dialogues (
-    chat_id BIGINT PRIMARY KEY,
-    dialogue BLOB NOT NULL
+    chat_id TEXT PRIMARY KEY,
+    dialogue BLOB NOT NULL,
+    expires_at BIGINT
 );
         "#,
         )
         .execute(&mut conn)
         .await?;
 
-        Ok(Arc::new(Self { pool, serializer }))
+        // Tables created by older versions of teloxide don't have `expires_at`.
+        let has_expires_at = sqlx::query(
+            "SELECT 1 FROM pragma_table_info('teloxide_dialogues') WHERE name = 'expires_at'",
+        )
+        .fetch_optional(&mut conn)
+        .await?
+        .is_some();
+
+        if !has_expires_at {
+            sqlx::query("ALTER TABLE teloxide_dialogues ADD COLUMN expires_at BIGINT")
+                .execute(&mut conn)
+                .await?;
+        }
+
+        // Tables created by older versions of teloxide have an integer key column,
+        // which turns keys like `007` into numbers.
+        let has_text_keys = sqlx::query(
+            "SELECT 1 FROM pragma_table_info('teloxide_dialogues') WHERE name = 'chat_id' AND \
+             type = 'TEXT'",
+        )
+        .fetch_optional(&mut conn)
+        .await?
+        .is_some();
+
+        drop(conn);
+        if !has_text_keys {
+            migrate_to_text_keys(&pool).await?;
+        }
+
+        remove_stale_dialogues(&pool).await?;
+
+        Ok(Arc::new(Self { pool, serializer, swept_at: Mutex::new(Instant::now()) }))
     }
 }
 
-impl<S, D> Storage<D> for SqliteStorage<S>
+impl<S, D, K> Storage<D, K> for SqliteStorage<S>
 where
     S: Send + Sync + Serializer<D> + 'static,
     D: Send + Serialize + DeserializeOwned + 'static,
+    K: Display + Send + 'static,
     <S as Serializer<D>>::Error: Debug + Display,
 {
     type Error = SqliteStorageError<<S as Serializer<D>>::Error>;
 
     /// Returns [`sqlx::Error::RowNotFound`] if a dialogue does not exist.
-    fn remove_dialogue(
-        self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>> {
         Box::pin(async move {
-            let deleted_rows_count =
-                sqlx::query("DELETE FROM teloxide_dialogues WHERE chat_id = ?")
-                    .bind(chat_id)
-                    .execute(&self.pool)
-                    .await?
-                    .rows_affected();
+            // An expired dialogue is the same as a missing one.
+            let deleted_rows_count = sqlx::query(
+                "DELETE FROM teloxide_dialogues WHERE chat_id = ? AND (expires_at IS NULL OR \
+                 expires_at > ?)",
+            )
+            .bind(key.to_string())
+            .bind(unix_time_millis())
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
 
             if deleted_rows_count == 0 {
                 return Err(SqliteStorageError::DialogueNotFound);
@@ -86,56 +139,383 @@ where
 
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: i64,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>> {
         Box::pin(async move {
             let d = self.serializer.serialize(&dialogue).map_err(SqliteStorageError::SerdeError)?;
+            update_dialogue(&self.pool, &key.to_string(), d, None).await?;
+            self.sweep().await?;
+            Ok(())
+        })
+    }
 
-            self.pool
-                .acquire()
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            get_dialogue(&self.pool, &key.to_string())
                 .await?
-                .execute(
-                    sqlx::query(
+                .map(|d| self.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError))
+                .transpose()
+        })
+    }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let d = self.serializer.serialize(&dialogue).map_err(SqliteStorageError::SerdeError)?;
+            let ttl = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+
+            let expires_at = unix_time_millis().saturating_add(ttl);
+            update_dialogue(&self.pool, &key.to_string(), d, Some(expires_at)).await?;
+            self.sweep().await?;
+            Ok(())
+        })
+    }
+
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        Box::pin(async move {
+            // Expired dialogues are kept until this function is called (or for
+            // `EXPIRED_DIALOGUE_RETENTION`), so it knows that the dialogue has expired.
+            let deleted_rows_count =
+                sqlx::query("DELETE FROM teloxide_dialogues WHERE chat_id = ? AND expires_at <= ?")
+                    .bind(key.to_string())
+                    .bind(unix_time_millis())
+                    .execute(&self.pool)
+                    .await?
+                    .rows_affected();
+
+            Ok(deleted_rows_count > 0)
+        })
+    }
+
+    fn update_dialogue_with<F>(
+        self: Arc<Self>,
+        key: K,
+        ttl: Option<Duration>,
+        mut f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        Box::pin(async move {
+            let key = key.to_string();
+
+            // A compare-and-swap: the dialogue is updated only if it is still the same as
+            // the one passed to `f`, otherwise we try again.
+            loop {
+                let current = get_dialogue(&self.pool, &key).await?;
+                let dialogue = current
+                    .as_deref()
+                    .map(|d| self.serializer.deserialize(d))
+                    .transpose()
+                    .and_then(|current| self.serializer.serialize(&f(current)))
+                    .map_err(SqliteStorageError::SerdeError)?;
+
+                let now = unix_time_millis();
+                let expires_at = ttl.map(|ttl| {
+                    now.saturating_add(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX))
+                });
+
+                let query = match current {
+                    Some(current) => sqlx::query(
                         r#"
-            INSERT INTO teloxide_dialogues VALUES (?, ?)
-            ON CONFLICT(chat_id) DO UPDATE SET dialogue=excluded.dialogue
+            UPDATE teloxide_dialogues SET dialogue = ?, expires_at = ?
+            WHERE chat_id = ? AND dialogue = ? AND (expires_at IS NULL OR expires_at > ?)
                                 "#,
                     )
-                    .bind(chat_id)
-                    .bind(d),
-                )
-                .await?;
-            Ok(())
+                    .bind(dialogue)
+                    .bind(expires_at)
+                    .bind(key.as_str())
+                    .bind(current)
+                    .bind(now),
+                    // An expired dialogue is the same as a missing one.
+                    None => sqlx::query(
+                        r#"
+            INSERT INTO teloxide_dialogues (chat_id, dialogue, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT(chat_id) DO UPDATE SET dialogue=excluded.dialogue, expires_at=excluded.expires_at
+            WHERE teloxide_dialogues.expires_at <= ?
+                                "#,
+                    )
+                    .bind(key.as_str())
+                    .bind(dialogue)
+                    .bind(expires_at)
+                    .bind(now),
+                };
+
+                if query.execute(&self.pool).await?.rows_affected() > 0 {
+                    self.sweep().await?;
+                    return Ok(());
+                }
+
+                log::trace!("A dialogue #{} has been modified concurrently, retrying", key);
+            }
+        })
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for SqliteStorage<S>
+where
+    S: Send + Sync + Serializer<D> + 'static,
+    D: Send + Serialize + DeserializeOwned + 'static,
+    K: Display + FromStr + Send + 'static,
+    <S as Serializer<D>>::Error: Debug + Display + Send + 'static,
+{
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        let state = (self, Some(0), VecDeque::new());
+
+        stream::unfold(state, |(this, mut after_rowid, mut buffer)| async move {
+            loop {
+                if let Some(dialogue) = buffer.pop_front() {
+                    return Some((dialogue, (this, after_rowid, buffer)));
+                }
+
+                match get_dialogues_page(&this.pool, after_rowid?).await {
+                    Ok(rows) => {
+                        after_rowid = match rows.last() {
+                            Some(row) if rows.len() == PAGE_SIZE => Some(row.rowid),
+                            _ => None,
+                        };
+                        buffer.extend(
+                            rows.iter().filter_map(|row| this.parse_row::<D, K>(row)).map(Ok),
+                        );
+                    }
+                    Err(err) => return Some((Err(err.into()), (this, None, buffer))),
+                }
+            }
+        })
+        .boxed()
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>> {
+        Box::pin(async move {
+            // Dialogues of other types have to be skipped, so the dialogues are parsed
+            // instead of using `COUNT(*)`.
+            let mut after_rowid = 0;
+            let mut count = 0;
+
+            loop {
+                let rows = get_dialogues_page(&self.pool, after_rowid).await?;
+                count += rows.iter().filter(|row| self.parse_row::<D, K>(row).is_some()).count();
+
+                match rows.last() {
+                    Some(row) if rows.len() == PAGE_SIZE => after_rowid = row.rowid,
+                    _ => return Ok(count),
+                }
+            }
         })
     }
 
-    fn get_dialogue(
+    fn remove_dialogues_where<P>(
         self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        mut predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static,
+    {
         Box::pin(async move {
-            get_dialogue(&self.pool, chat_id)
-                .await?
-                .map(|d| self.serializer.deserialize(&d).map_err(SqliteStorageError::SerdeError))
-                .transpose()
+            let mut after_rowid = 0;
+            let mut removed = 0;
+
+            loop {
+                let rows = get_dialogues_page(&self.pool, after_rowid).await?;
+
+                for row in &rows {
+                    let (key, dialogue) = match self.parse_row::<D, K>(row) {
+                        Some(parsed) => parsed,
+                        None => continue,
+                    };
+
+                    if predicate(&key, &dialogue) {
+                        // The dialogue is removed only if it hasn't been modified since it was
+                        // fetched.
+                        removed += sqlx::query(
+                            "DELETE FROM teloxide_dialogues WHERE rowid = ? AND dialogue = ?",
+                        )
+                        .bind(row.rowid)
+                        .bind(row.dialogue.as_slice())
+                        .execute(&self.pool)
+                        .await?
+                        .rows_affected() as usize;
+                    }
+                }
+
+                match rows.last() {
+                    Some(row) if rows.len() == PAGE_SIZE => after_rowid = row.rowid,
+                    _ => return Ok(removed),
+                }
+            }
         })
     }
 }
 
-async fn get_dialogue(pool: &SqlitePool, chat_id: i64) -> Result<Option<Vec<u8>>, sqlx::Error> {
+impl<S> SqliteStorage<S> {
+    /// Removes dialogues which have expired more than
+    /// [`EXPIRED_DIALOGUE_RETENTION`] ago, once in [`SWEEP_INTERVAL`].
+    async fn sweep(&self) -> Result<(), sqlx::Error> {
+        {
+            let now = Instant::now();
+            let mut swept_at = self.swept_at.lock().unwrap();
+
+            if now.saturating_duration_since(*swept_at) < SWEEP_INTERVAL {
+                return Ok(());
+            }
+            *swept_at = now;
+        }
+
+        remove_stale_dialogues(&self.pool).await
+    }
+
+    /// Parses a dialogue fetched by an [`EnumerableStorage`] method.
+    ///
+    /// Returns `None` if the key can't be parsed as `K` or the dialogue can't
+    /// be deserialized as `D`, e.g. if it has been stored by a storage of
+    /// another type which shares the table.
+    fn parse_row<D, K>(&self, row: &DialogueRow) -> Option<(K, D)>
+    where
+        S: Serializer<D>,
+        K: FromStr,
+        <S as Serializer<D>>::Error: Display,
+    {
+        let key = row.dialogue_key.parse().ok()?;
+
+        match self.serializer.deserialize(&row.dialogue) {
+            Ok(dialogue) => Some((key, dialogue)),
+            Err(err) => {
+                log::debug!("Skipping a dialogue #{} of another type: {}", row.dialogue_key, err);
+                None
+            }
+        }
+    }
+}
+
+/// The number of dialogues which are fetched at once by [`EnumerableStorage`]
+/// methods.
+const PAGE_SIZE: usize = 100;
+
+#[derive(sqlx::FromRow)]
+struct DialogueRow {
+    rowid: i64,
+    dialogue_key: String,
+    dialogue: Vec<u8>,
+}
+
+/// Returns up to [`PAGE_SIZE`] dialogues which haven't expired, ordered by
+/// `rowid` and starting after `after_rowid`.
+async fn get_dialogues_page(
+    pool: &SqlitePool,
+    after_rowid: i64,
+) -> Result<Vec<DialogueRow>, sqlx::Error> {
+    sqlx::query_as::<_, DialogueRow>(
+        r#"
+            SELECT rowid, chat_id AS dialogue_key, dialogue FROM teloxide_dialogues
+            WHERE rowid > ? AND (expires_at IS NULL OR expires_at > ?)
+            ORDER BY rowid LIMIT ?
+                                "#,
+    )
+    .bind(after_rowid)
+    .bind(unix_time_millis())
+    .bind(PAGE_SIZE as i64)
+    .fetch_all(pool)
+    .await
+}
+
+async fn update_dialogue(
+    pool: &SqlitePool,
+    key: &str,
+    dialogue: Vec<u8>,
+    expires_at: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    pool.acquire()
+        .await?
+        .execute(
+            sqlx::query(
+                r#"
+            INSERT INTO teloxide_dialogues (chat_id, dialogue, expires_at) VALUES (?, ?, ?)
+            ON CONFLICT(chat_id) DO UPDATE SET dialogue=excluded.dialogue, expires_at=excluded.expires_at
+                                "#,
+            )
+            .bind(key)
+            .bind(dialogue)
+            .bind(expires_at),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn get_dialogue(pool: &SqlitePool, key: &str) -> Result<Option<Vec<u8>>, sqlx::Error> {
     #[derive(sqlx::FromRow)]
     struct DialogueDbRow {
         dialogue: Vec<u8>,
     }
 
     let bytes = sqlx::query_as::<_, DialogueDbRow>(
-        "SELECT dialogue FROM teloxide_dialogues WHERE chat_id = ?",
+        "SELECT dialogue FROM teloxide_dialogues WHERE chat_id = ? AND (expires_at IS NULL OR \
+         expires_at > ?)",
     )
-    .bind(chat_id)
+    .bind(key)
+    .bind(unix_time_millis())
     .fetch_optional(pool)
     .await?
     .map(|r| r.dialogue);
 
     Ok(bytes)
 }
+
+/// Recreates `teloxide_dialogues` with a `TEXT` key column, copying the
+/// dialogues.
+async fn migrate_to_text_keys(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    log::info!("Migrating teloxide_dialogues to text keys");
+
+    let mut tx = pool.begin().await?;
+    tx.execute(
+        r#"
+CREATE TABLE teloxide_dialogues_new (
+    chat_id TEXT PRIMARY KEY,
+    dialogue BLOB NOT NULL,
+    expires_at BIGINT
+);
+        "#,
+    )
+    .await?;
+    tx.execute(
+        r#"
+INSERT INTO teloxide_dialogues_new (chat_id, dialogue, expires_at)
+SELECT CAST(chat_id AS TEXT), dialogue, expires_at FROM teloxide_dialogues;
+        "#,
+    )
+    .await?;
+    tx.execute("DROP TABLE teloxide_dialogues").await?;
+    tx.execute("ALTER TABLE teloxide_dialogues_new RENAME TO teloxide_dialogues").await?;
+    tx.commit().await
+}
+
+/// Removes dialogues which have expired more than
+/// [`EXPIRED_DIALOGUE_RETENTION`] ago.
+async fn remove_stale_dialogues(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let retention = i64::try_from(EXPIRED_DIALOGUE_RETENTION.as_millis()).unwrap_or(i64::MAX);
+
+    sqlx::query("DELETE FROM teloxide_dialogues WHERE expires_at <= ?")
+        .bind(unix_time_millis().saturating_sub(retention))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+fn unix_time_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as i64)
+        .unwrap_or_default()
+}
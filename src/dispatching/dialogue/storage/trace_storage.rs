@@ -2,11 +2,12 @@ use std::{
     fmt::Debug,
     marker::{Send, Sync},
     sync::Arc,
+    time::Duration,
 };
 
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, stream::BoxStream};
 
-use crate::dispatching::dialogue::Storage;
+use crate::dispatching::dialogue::{EnumerableStorage, Storage};
 
 /// A dialogue storage wrapper which logs all actions performed on an underlying
 /// storage.
@@ -27,24 +28,25 @@ impl<S> TraceStorage<S> {
     }
 }
 
-impl<S, D> Storage<D> for TraceStorage<S>
+impl<S, D, K> Storage<D, K> for TraceStorage<S>
 where
     D: Debug,
-    S: Storage<D> + Send + Sync + 'static,
+    K: Debug + Send + 'static,
+    S: Storage<D, K> + Send + Sync + 'static,
 {
-    type Error = <S as Storage<D>>::Error;
+    type Error = <S as Storage<D, K>>::Error;
 
-    fn remove_dialogue(self: Arc<Self>, chat_id: i64) -> BoxFuture<'static, Result<(), Self::Error>>
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
     where
         D: Send + 'static,
     {
-        log::trace!("Removing dialogue #{}", chat_id);
-        <S as Storage<D>>::remove_dialogue(self.inner.clone(), chat_id)
+        log::trace!("Removing dialogue #{:?}", key);
+        <S as Storage<D, K>>::remove_dialogue(self.inner.clone(), key)
     }
 
     fn update_dialogue(
         self: Arc<Self>,
-        chat_id: i64,
+        key: K,
         dialogue: D,
     ) -> BoxFuture<'static, Result<(), Self::Error>>
     where
@@ -52,17 +54,87 @@ where
     {
         Box::pin(async move {
             let to = format!("{:#?}", dialogue);
-            <S as Storage<D>>::update_dialogue(self.inner.clone(), chat_id, dialogue).await?;
-            log::trace!("Updated a dialogue #{}: {:#?}", chat_id, to);
+            let from = format!("{:?}", key);
+            <S as Storage<D, K>>::update_dialogue(self.inner.clone(), key, dialogue).await?;
+            log::trace!("Updated a dialogue #{}: {:#?}", from, to);
             Ok(())
         })
     }
 
-    fn get_dialogue(
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        log::trace!("Requested a dialogue #{:?}", key);
+        <S as Storage<D, K>>::get_dialogue(self.inner.clone(), key)
+    }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        Box::pin(async move {
+            let to = format!("{:#?}", dialogue);
+            let from = format!("{:?}", key);
+            <S as Storage<D, K>>::update_dialogue_with_ttl(self.inner.clone(), key, dialogue, ttl)
+                .await?;
+            log::trace!("Updated a dialogue #{} for {:?}: {:#?}", from, ttl, to);
+            Ok(())
+        })
+    }
+
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        log::trace!("Checking if a dialogue #{:?} has expired", key);
+        <S as Storage<D, K>>::take_expired(self.inner.clone(), key)
+    }
+
+    fn update_dialogue_with<F>(
         self: Arc<Self>,
-        chat_id: i64,
-    ) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
-        log::trace!("Requested a dialogue #{}", chat_id);
-        <S as Storage<D>>::get_dialogue(self.inner.clone(), chat_id)
+        key: K,
+        ttl: Option<Duration>,
+        f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        log::trace!("Updating a dialogue #{:?} with a function", key);
+        <S as Storage<D, K>>::update_dialogue_with(self.inner.clone(), key, ttl, f)
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for TraceStorage<S>
+where
+    D: Debug,
+    K: Debug + Send + 'static,
+    S: EnumerableStorage<D, K> + Send + Sync + 'static,
+{
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        log::trace!("Requested all dialogues");
+        <S as EnumerableStorage<D, K>>::dialogues(self.inner.clone())
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>> {
+        log::trace!("Requested the number of dialogues");
+        <S as EnumerableStorage<D, K>>::count_dialogues(self.inner.clone())
+    }
+
+    fn remove_dialogues_where<P>(
+        self: Arc<Self>,
+        predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static,
+    {
+        log::trace!("Removing dialogues by a predicate");
+        <S as EnumerableStorage<D, K>>::remove_dialogues_where(self.inner.clone(), predicate)
     }
 }
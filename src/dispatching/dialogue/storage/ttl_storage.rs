@@ -0,0 +1,204 @@
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, stream::BoxStream};
+
+use crate::dispatching::dialogue::{EnumerableStorage, Storage};
+
+type ExpiryHandler<K> = Arc<dyn Fn(K) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// A dialogue storage wrapper which makes dialogues expire after a period of
+/// inactivity.
+///
+/// Each time a dialogue is updated, it is stored with [`TtlStorage::ttl`] via
+/// [`Storage::update_dialogue_with_ttl`], so an abandoned dialogue is reset
+/// instead of being stuck in an intermediate state forever.
+///
+/// If an expiry handler is set (see [`TtlStorage::with_expiry_handler`]), it is
+/// called with a key of a dialogue when the dialogue is requested and it turns
+/// out that the dialogue has expired, e.g. to tell a user that their session
+/// has timed out.
+pub struct TtlStorage<S, K = i64> {
+    inner: Arc<S>,
+    ttl: Duration,
+    on_expire: Option<ExpiryHandler<K>>,
+}
+
+impl<S, K> TtlStorage<S, K> {
+    #[must_use]
+    pub fn new(inner: Arc<S>, ttl: Duration) -> Arc<Self> {
+        Arc::new(Self { inner, ttl, on_expire: None })
+    }
+
+    /// Like [`TtlStorage::new`] but calls `handler` with a key of each expired
+    /// dialogue.
+    #[must_use]
+    pub fn with_expiry_handler<H, Fut>(inner: Arc<S>, ttl: Duration, handler: H) -> Arc<Self>
+    where
+        H: Fn(K) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let on_expire: ExpiryHandler<K> = Arc::new(move |key| Box::pin(handler(key)));
+        Arc::new(Self { inner, ttl, on_expire: Some(on_expire) })
+    }
+
+    /// The period after which an updated dialogue expires.
+    #[must_use]
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn into_inner(self) -> Arc<S> {
+        self.inner
+    }
+}
+
+impl<S, K> Debug for TtlStorage<S, K>
+where
+    S: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TtlStorage")
+            .field("inner", &self.inner)
+            .field("ttl", &self.ttl)
+            .field("on_expire", &self.on_expire.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl<S, D, K> Storage<D, K> for TtlStorage<S, K>
+where
+    S: Storage<D, K> + Send + Sync + 'static,
+    <S as Storage<D, K>>::Error: Send + 'static,
+    D: Send + 'static,
+    K: Clone + Debug + Send + 'static,
+{
+    type Error = <S as Storage<D, K>>::Error;
+
+    fn remove_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        <S as Storage<D, K>>::remove_dialogue(self.inner.clone(), key)
+    }
+
+    fn update_dialogue(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        <S as Storage<D, K>>::update_dialogue_with_ttl(self.inner.clone(), key, dialogue, self.ttl)
+    }
+
+    fn get_dialogue(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<Option<D>, Self::Error>> {
+        Box::pin(async move {
+            let dialogue =
+                <S as Storage<D, K>>::get_dialogue(self.inner.clone(), key.clone()).await?;
+
+            if let (None, Some(on_expire)) = (&dialogue, &self.on_expire) {
+                if <S as Storage<D, K>>::take_expired(self.inner.clone(), key.clone()).await? {
+                    log::debug!("A dialogue #{:?} has expired", key);
+                    on_expire(key).await;
+                }
+            }
+
+            Ok(dialogue)
+        })
+    }
+
+    fn update_dialogue_with_ttl(
+        self: Arc<Self>,
+        key: K,
+        dialogue: D,
+        ttl: Duration,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        D: Send + 'static,
+    {
+        <S as Storage<D, K>>::update_dialogue_with_ttl(self.inner.clone(), key, dialogue, ttl)
+    }
+
+    fn take_expired(self: Arc<Self>, key: K) -> BoxFuture<'static, Result<bool, Self::Error>>
+    where
+        Self::Error: Send + 'static,
+    {
+        <S as Storage<D, K>>::take_expired(self.inner.clone(), key)
+    }
+
+    fn update_dialogue_with<F>(
+        self: Arc<Self>,
+        key: K,
+        ttl: Option<Duration>,
+        f: F,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>
+    where
+        Self: Send + Sync + 'static,
+        Self::Error: Send + 'static,
+        D: Send + 'static,
+        K: Clone + Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        let ttl = Some(ttl.unwrap_or(self.ttl));
+        <S as Storage<D, K>>::update_dialogue_with(self.inner.clone(), key, ttl, f)
+    }
+}
+
+impl<S, D, K> EnumerableStorage<D, K> for TtlStorage<S, K>
+where
+    S: EnumerableStorage<D, K> + Send + Sync + 'static,
+    <S as Storage<D, K>>::Error: Send + 'static,
+    D: Send + 'static,
+    K: Clone + Debug + Send + 'static,
+{
+    fn dialogues(self: Arc<Self>) -> BoxStream<'static, Result<(K, D), Self::Error>> {
+        <S as EnumerableStorage<D, K>>::dialogues(self.inner.clone())
+    }
+
+    fn count_dialogues(self: Arc<Self>) -> BoxFuture<'static, Result<usize, Self::Error>> {
+        <S as EnumerableStorage<D, K>>::count_dialogues(self.inner.clone())
+    }
+
+    fn remove_dialogues_where<P>(
+        self: Arc<Self>,
+        predicate: P,
+    ) -> BoxFuture<'static, Result<usize, Self::Error>>
+    where
+        P: FnMut(&K, &D) -> bool + Send + 'static,
+    {
+        <S as EnumerableStorage<D, K>>::remove_dialogues_where(self.inner.clone(), predicate)
+    }
+}
+
+#[tokio::test]
+async fn ttl_storage_calls_expiry_handler() {
+    use crate::dispatching::dialogue::InMemStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let expired = Arc::new(AtomicUsize::new(0));
+    let storage = {
+        let expired = Arc::clone(&expired);
+        TtlStorage::with_expiry_handler(
+            InMemStorage::<i32>::new(),
+            Duration::from_millis(50),
+            move |_| {
+                expired.fetch_add(1, Ordering::SeqCst);
+                async {}
+            },
+        )
+    };
+
+    Arc::clone(&storage).update_dialogue(1, 42).await.unwrap();
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), Some(42));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), None);
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), None);
+    assert_eq!(expired.load(Ordering::SeqCst), 1);
+}
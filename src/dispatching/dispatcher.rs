@@ -257,6 +257,7 @@ where
     where
         R: Requester + Clone,
         <R as Requester>::GetUpdates: Send,
+        <R as Requester>::Err: update_listeners::PollingError,
     {
         let listener = update_listeners::polling_default(self.requester.clone()).await;
         let error_handler =
@@ -32,6 +32,7 @@ where
     N: Into<String> + Send + 'static,
     R: Requester + Send + Clone + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: update_listeners::PollingError,
 {
     let cloned_requester = requester.clone();
 
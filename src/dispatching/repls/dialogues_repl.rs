@@ -30,6 +30,7 @@ where
     Fut: Future<Output = DialogueStage<D>> + Send + 'static,
     R: Requester + Send + Clone + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: update_listeners::PollingError,
 {
     let cloned_requester = requester.clone();
 
@@ -29,6 +29,7 @@ where
     E: Debug + Send,
     R: Requester + Send + Clone + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: update_listeners::PollingError,
 {
     let cloned_requester = requester.clone();
     repl_with_listener(
@@ -53,6 +53,22 @@ impl StopToken for AsyncStopToken {
     }
 }
 
+/// A pair of stop tokens is a stop token which stops both listeners, e.g.
+/// the one returned from [`UpdateListenerExt::merge`].
+///
+/// [`UpdateListenerExt::merge`]:
+/// crate::dispatching::update_listeners::UpdateListenerExt::merge
+impl<A, B> StopToken for (A, B)
+where
+    A: StopToken,
+    B: StopToken,
+{
+    fn stop(self) {
+        self.0.stop();
+        self.1.stop();
+    }
+}
+
 impl AsyncStopFlag {
     /// Returns true if the stop token linked to `self` was used.
     pub fn is_stopped(&self) -> bool {
@@ -4,24 +4,54 @@
 //! using one these functions:
 //!
 //! - [`polling_default`], which returns a default long polling listener.
-//! - [`polling`], which returns a long polling listener with your
-//!   configuration.
+//! - [`polling`], which returns a long polling listener with your configuration
+//!   (or [`PollingBuilder`] for more options).
+//! - [`webhook`], which returns a webhook listener (requires the `webhooks`
+//!   feature).
+//! - [`channel`], which returns a listener of updates sent by your code.
+//! - [`replay`], which returns a listener of updates recorded with
+//!   [`UpdateListenerExt::record`].
+//! - [`redis_stream`], which returns a listener of updates added to a Redis
+//!   stream by a [`RedisStreamProducer`] (requires the `redis-storage`
+//!   feature).
 //!
 //! And then you can extract updates from it or pass them directly to a
-//! [`Dispatcher`].
+//! [`Dispatcher`]. Listeners can be combined and adjusted with
+//! [`UpdateListenerExt`], e.g. to merge two listeners.
 //!
 //! Telegram supports two ways of [getting updates]: [long polling] and
-//! [webhooks]. Currently, only the former one is implemented (see [`polling()`]
-//! and [`polling_default`]). See also [README FAQ about webhooks](https://github.com/teloxide/teloxide/blob/master/README.md#faq).
+//! [webhooks]. The former is implemented by [`polling()`] and
+//! [`polling_default`], the latter by [`webhook()`]. See also [README FAQ about webhooks](https://github.com/teloxide/teloxide/blob/master/README.md#faq).
 //!
 //! [`UpdateListener`]: UpdateListener
 //! [`polling_default`]: polling_default
 //! [`polling`]: polling()
+//! [`channel`]: channel()
 //! [`Dispatcher`]: crate::dispatching::Dispatcher
 //! [`Box::get_updates`]: crate::requests::Requester::get_updates
 //! [getting updates]: https://core.telegram.org/bots/api#getting-updates
 //! [long polling]: https://en.wikipedia.org/wiki/Push_technology#Long_polling
 //! [webhooks]: https://en.wikipedia.org/wiki/Webhook
+#![cfg_attr(feature = "webhooks", doc = "[`webhook`]: webhook()")]
+#![cfg_attr(feature = "webhooks", doc = "[`webhook()`]: webhook()")]
+#![cfg_attr(
+    not(feature = "webhooks"),
+    doc = "[`webhook`]: https://docs.rs/teloxide/latest/teloxide/dispatching/update_listeners/fn.webhook.html"
+)]
+#![cfg_attr(
+    not(feature = "webhooks"),
+    doc = "[`webhook()`]: https://docs.rs/teloxide/latest/teloxide/dispatching/update_listeners/fn.webhook.html"
+)]
+#![cfg_attr(feature = "redis-storage", doc = "[`redis_stream`]: redis_stream()")]
+#![cfg_attr(feature = "redis-storage", doc = "[`RedisStreamProducer`]: RedisStreamProducer")]
+#![cfg_attr(
+    not(feature = "redis-storage"),
+    doc = "[`redis_stream`]: https://docs.rs/teloxide/latest/teloxide/dispatching/update_listeners/fn.redis_stream.html"
+)]
+#![cfg_attr(
+    not(feature = "redis-storage"),
+    doc = "[`RedisStreamProducer`]: https://docs.rs/teloxide/latest/teloxide/dispatching/update_listeners/struct.RedisStreamProducer.html"
+)]
 
 use futures::Stream;
 
@@ -32,12 +62,42 @@ use crate::{
     types::{AllowedUpdate, Update},
 };
 
+mod acknowledger;
+mod channel;
+mod dedup;
+mod listener_ext;
+mod offset_store;
 mod polling;
+mod record;
+#[cfg(feature = "redis-storage")]
+mod redis_stream;
 mod stateful_listener;
+mod stats;
+#[cfg(test)]
+mod test_utils;
+#[cfg(feature = "webhooks")]
+mod webhook;
 
 pub use self::{
-    polling::{polling, polling_default},
+    acknowledger::Acknowledger,
+    channel::{channel, UpdateSender},
+    dedup::{Dedup, DedupStore, StorageDedupStore},
+    listener_ext::{Filter, Inspect, Map, MapErr, Merge, UpdateListenerExt},
+    offset_store::{FileOffsetStore, OffsetStore, SavedOffset, StorageOffsetStore},
+    polling::{polling, polling_default, Backoff, PollingBuilder, PollingError, PollingErrorKind},
+    record::{replay, Record},
     stateful_listener::StatefulListener,
+    stats::ListenerStats,
+};
+
+#[cfg(feature = "webhooks")]
+#[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "webhooks")))]
+pub use self::webhook::{webhook, AsBot, Options as WebhookOptions};
+
+#[cfg(feature = "redis-storage")]
+#[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "redis-storage")))]
+pub use self::redis_stream::{
+    redis_stream, RedisStreamError, RedisStreamOptions, RedisStreamProducer,
 };
 
 /// An update listener.
@@ -97,6 +157,21 @@ pub trait UpdateListener<E>: for<'a> AsUpdateStream<'a, E> {
     fn timeout_hint(&self) -> Option<Duration> {
         None
     }
+
+    /// Returns a handle which is used to acknowledge handled updates.
+    ///
+    /// A consumer of updates which supports acknowledgements (e.g. the
+    /// `dispatching2` dispatcher) calls this function before creating the
+    /// update stream and then acknowledges every update once it has been
+    /// handled. This allows the listener to keep updates until they have been
+    /// handled, e.g. a [`PollingBuilder::offset_store`] saves unhandled
+    /// updates, so that they aren't lost after a crash.
+    ///
+    /// Listeners which don't need acknowledgements return `None`, which is the
+    /// default implementation.
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        None
+    }
 }
 
 /// [`UpdateListener`]'s supertrait/extension.
@@ -0,0 +1,31 @@
+use std::{future::Future, sync::Arc};
+
+use futures::future::BoxFuture;
+
+/// A handle which is used to acknowledge that updates have been handled.
+///
+/// See [`UpdateListener::acknowledger`].
+///
+/// [`UpdateListener::acknowledger`]: crate::dispatching::update_listeners::UpdateListener::acknowledger
+#[derive(Clone)]
+pub struct Acknowledger(Arc<dyn Fn(i32) -> BoxFuture<'static, ()> + Send + Sync>);
+
+impl Acknowledger {
+    /// Creates an acknowledger which calls `f` with an ID of every
+    /// acknowledged update.
+    pub fn new<F, Fut>(f: F) -> Self
+    where
+        F: Fn(i32) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self(Arc::new(move |update_id| Box::pin(f(update_id))))
+    }
+
+    /// Acknowledges that the update with the `update_id` ID has been handled.
+    ///
+    /// Every update should be acknowledged at most once.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    pub fn ack(&self, update_id: i32) -> BoxFuture<'static, ()> {
+        (self.0)(update_id)
+    }
+}
@@ -0,0 +1,127 @@
+use std::convert::Infallible;
+
+use futures::{
+    future::{self, Either},
+    stream::{self, Stream},
+};
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+};
+
+use crate::{
+    dispatching::{
+        stop_token::{AsyncStopFlag, AsyncStopToken, StopToken},
+        update_listeners::{stateful_listener::StatefulListener, UpdateListener},
+    },
+    types::Update,
+};
+
+/// A handle which sends updates to a [`channel`] listener.
+///
+/// The handle can be cloned to send updates from multiple places.
+#[derive(Debug, Clone)]
+pub struct UpdateSender(mpsc::Sender<Update>);
+
+impl UpdateSender {
+    /// Sends an update to the listener, waiting until there is space in the
+    /// buffer.
+    ///
+    /// Returns the update back if the listener has been stopped or dropped.
+    pub async fn send(&self, update: Update) -> Result<(), SendError<Update>> {
+        self.0.send(update).await
+    }
+
+    /// Tries to send an update to the listener without waiting.
+    ///
+    /// Returns the update back if the buffer is full or if the listener has
+    /// been stopped or dropped.
+    pub fn try_send(&self, update: Update) -> Result<(), TrySendError<Update>> {
+        self.0.try_send(update)
+    }
+
+    /// Returns `true` if the listener has been stopped or dropped, so updates
+    /// can't be sent anymore.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+}
+
+/// Returns an update listener which returns updates sent via the returned
+/// [`UpdateSender`].
+///
+/// This allows to push updates into a dispatcher from other parts of an
+/// application, e.g. from tests or from a queue consumer. To receive updates
+/// from Telegram at the same time, [merge] this listener with another one.
+///
+/// The listener buffers up to `buffer` updates. When the listener is stopped,
+/// it stops accepting new updates, returns the buffered ones and then ends.
+/// The listener also ends when all the senders are dropped.
+///
+/// ## Panics
+///
+/// If `buffer` is 0.
+///
+/// [merge]: crate::dispatching::update_listeners::UpdateListenerExt::merge
+pub fn channel(
+    buffer: usize,
+) -> (UpdateSender, impl UpdateListener<Infallible, StopToken = impl Send + StopToken>) {
+    struct State {
+        rx: mpsc::Receiver<Update>,
+        flag: AsyncStopFlag,
+        token: AsyncStopToken,
+    }
+
+    fn stream(st: &mut State) -> impl Stream<Item = Result<Update, Infallible>> + Send + '_ {
+        stream::unfold(st, |state| async move {
+            if !state.flag.is_stopped() {
+                let update = {
+                    let recv = state.rx.recv();
+                    tokio::pin!(recv);
+
+                    match future::select(recv, &mut state.flag).await {
+                        Either::Left((update, _)) => Some(update),
+                        Either::Right(_) => None,
+                    }
+                };
+
+                if let Some(update) = update {
+                    return update.map(|upd| (Ok(upd), state));
+                }
+            }
+
+            // The listener is stopped, so don't accept new updates, but return the
+            // buffered ones.
+            state.rx.close();
+            state.rx.recv().await.map(|upd| (Ok(upd), state))
+        })
+    }
+
+    let (tx, rx) = mpsc::channel(buffer);
+    let (token, flag) = AsyncStopToken::new_pair();
+
+    let state = State { rx, flag, token };
+    let stop_token = |st: &mut State| st.token.clone();
+
+    (UpdateSender(tx), StatefulListener::new(state, stream, stop_token))
+}
+
+#[tokio::test]
+async fn channel_returns_buffered_updates_after_stop() {
+    use futures::StreamExt;
+
+    use crate::dispatching::update_listeners::{test_utils::update, AsUpdateStream};
+
+    let (tx, mut listener) = channel(8);
+
+    tx.send(update(1)).await.unwrap();
+    tx.send(update(2)).await.unwrap();
+    listener.stop_token().stop();
+
+    let ids: Vec<_> = listener.as_stream().map(|res| res.unwrap().id).collect().await;
+    assert_eq!(ids, [1, 2]);
+
+    assert!(tx.is_closed());
+    assert!(tx.send(update(3)).await.is_err());
+}
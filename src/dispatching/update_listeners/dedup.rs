@@ -0,0 +1,315 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{Debug, Display},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    stream::{self, BoxStream, StreamExt},
+};
+
+use crate::{
+    dispatching::{
+        dialogue::Storage,
+        update_listeners::{Acknowledger, AsUpdateStream, UpdateListener},
+    },
+    types::{AllowedUpdate, Update},
+};
+
+/// A storage of IDs of recently seen updates.
+///
+/// Allows [`Dedup`] to drop duplicates of updates which were received before
+/// a restart, see [`Dedup::store`].
+pub trait DedupStore {
+    type Error;
+
+    /// Returns the saved IDs, from the oldest to the newest.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn load_seen(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<i32>, Self::Error>>;
+
+    /// Saves `ids`, from the oldest to the newest.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn save_seen(self: Arc<Self>, ids: Vec<i32>) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+/// A dedup store which keeps the IDs in a dialogue [`Storage`].
+///
+/// The IDs are kept under the key `teloxide_dedup:{name}`, so they don't clash
+/// with dialogues, which are indexed by chat IDs, even if they share the
+/// storage. Use different `name`s for different bots.
+pub struct StorageDedupStore<S> {
+    storage: Arc<S>,
+    key: String,
+}
+
+impl<S> StorageDedupStore<S> {
+    #[must_use]
+    pub fn new(storage: Arc<S>, name: impl Display) -> Arc<Self> {
+        Arc::new(Self { storage, key: format!("{}{}", KEY_PREFIX, name) })
+    }
+}
+
+/// A prefix of keys of [`StorageDedupStore`].
+const KEY_PREFIX: &str = "teloxide_dedup:";
+
+impl<S> DedupStore for StorageDedupStore<S>
+where
+    S: Storage<Vec<i32>, String>,
+{
+    type Error = S::Error;
+
+    fn load_seen(self: Arc<Self>) -> BoxFuture<'static, Result<Vec<i32>, Self::Error>> {
+        let fut = Arc::clone(&self.storage).get_dialogue(self.key.clone());
+        Box::pin(async move { fut.await.map(Option::unwrap_or_default) })
+    }
+
+    fn save_seen(self: Arc<Self>, ids: Vec<i32>) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Arc::clone(&self.storage).update_dialogue(self.key.clone(), ids)
+    }
+}
+
+/// A listener returned from [`UpdateListenerExt::dedup`].
+///
+/// [`UpdateListenerExt::dedup`]: crate::dispatching::update_listeners::UpdateListenerExt::dedup
+#[must_use]
+pub struct Dedup<L> {
+    listener: L,
+    seen: Window,
+    saver: Option<Saver>,
+    loaded: bool,
+}
+
+/// How often IDs are saved while the underlying listener keeps returning
+/// updates.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+impl<L> Dedup<L> {
+    pub(crate) fn new(listener: L, window: usize) -> Self {
+        Self { listener, seen: Window::new(window), saver: None, loaded: false }
+    }
+
+    /// Persists IDs of seen updates in `store`, so duplicates are also dropped
+    /// after a restart.
+    ///
+    /// The IDs are loaded when the listener starts streaming updates. They are
+    /// saved in batches: when the underlying listener has no more updates
+    /// ready, but at least every second if it keeps returning updates, and
+    /// when it ends. So after a crash, duplicates of updates which were
+    /// received shortly before it aren't dropped.
+    pub fn store<S>(self, store: Arc<S>) -> Self
+    where
+        S: DedupStore + Send + Sync + 'static,
+        S::Error: Debug,
+    {
+        Self { saver: Some(Saver { store, unsaved: false, saved_at: Instant::now() }), ..self }
+    }
+}
+
+impl<'a, L, E> AsUpdateStream<'a, E> for Dedup<L>
+where
+    L: AsUpdateStream<'a, E>,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let Self { listener, seen, saver, loaded } = self;
+        let updates = Box::pin(listener.as_stream());
+
+        Box::pin(stream::unfold(
+            (updates, seen, saver, loaded),
+            |(mut updates, seen, saver, loaded)| async move {
+                if !*loaded {
+                    *loaded = true;
+
+                    if let Some(saver) = saver.as_ref() {
+                        for id in Arc::clone(&saver.store).load().await {
+                            seen.insert(id);
+                        }
+                    }
+                }
+
+                loop {
+                    let next = match updates.next().now_or_never() {
+                        Some(next) => next,
+                        None => {
+                            // Save the IDs while waiting for new updates anyway.
+                            if let Some(saver) = saver.as_mut() {
+                                saver.save(seen).await;
+                            }
+
+                            updates.next().await
+                        }
+                    };
+
+                    let upd = match next {
+                        Some(Ok(upd)) => upd,
+                        Some(Err(err)) => return Some((Err(err), (updates, seen, saver, loaded))),
+                        None => {
+                            if let Some(saver) = saver.as_mut() {
+                                saver.save(seen).await;
+                            }
+
+                            return None;
+                        }
+                    };
+
+                    // The duplicate isn't acknowledged, since the first copy of the update is.
+                    if !seen.insert(upd.id) {
+                        log::debug!("Dropped a duplicate of the update {}", upd.id);
+                        continue;
+                    }
+
+                    if let Some(saver) = saver.as_mut() {
+                        saver.unsaved = true;
+                        if saver.saved_at.elapsed() >= SAVE_INTERVAL {
+                            saver.save(seen).await;
+                        }
+                    }
+
+                    return Some((Ok(upd), (updates, seen, saver, loaded)));
+                }
+            },
+        ))
+    }
+}
+
+impl<L, E> UpdateListener<E> for Dedup<L>
+where
+    Self: for<'a> AsUpdateStream<'a, E>,
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.listener.hint_allowed_updates(hint)
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        self.listener.acknowledger()
+    }
+}
+
+/// IDs of the last `capacity` seen updates.
+struct Window {
+    capacity: usize,
+    order: VecDeque<i32>,
+    ids: HashSet<i32>,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, order: VecDeque::new(), ids: HashSet::new() }
+    }
+
+    /// Returns `false` if `id` has already been seen.
+    fn insert(&mut self, id: i32) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+
+    fn ids(&self) -> Vec<i32> {
+        self.order.iter().copied().collect()
+    }
+}
+
+/// A [`DedupStore`] along with the state of saving to it.
+struct Saver {
+    store: Arc<dyn LoggingDedupStore>,
+    /// Whether there are seen IDs which haven't been saved yet.
+    unsaved: bool,
+    saved_at: Instant,
+}
+
+impl Saver {
+    /// Saves `seen`, if it has changed since the last save.
+    async fn save(&mut self, seen: &Window) {
+        if self.unsaved {
+            Arc::clone(&self.store).save(seen.ids()).await;
+            self.unsaved = false;
+            self.saved_at = Instant::now();
+        }
+    }
+}
+
+/// A [`DedupStore`] which logs errors instead of returning them.
+trait LoggingDedupStore: Send + Sync {
+    fn load(self: Arc<Self>) -> BoxFuture<'static, Vec<i32>>;
+
+    fn save(self: Arc<Self>, ids: Vec<i32>) -> BoxFuture<'static, ()>;
+}
+
+impl<S> LoggingDedupStore for S
+where
+    S: DedupStore + Send + Sync + 'static,
+    S::Error: Debug,
+{
+    fn load(self: Arc<Self>) -> BoxFuture<'static, Vec<i32>> {
+        let fut = self.load_seen();
+
+        Box::pin(async move {
+            fut.await.unwrap_or_else(|err| {
+                log::error!("Failed to load IDs of seen updates: {:?}", err);
+                Vec::new()
+            })
+        })
+    }
+
+    fn save(self: Arc<Self>, ids: Vec<i32>) -> BoxFuture<'static, ()> {
+        let fut = self.save_seen(ids);
+
+        Box::pin(async move {
+            if let Err(err) = fut.await {
+                log::error!("Failed to save IDs of seen updates: {:?}", err);
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn dedup_drops_seen_updates() {
+    use crate::dispatching::{
+        dialogue::InMemStorage,
+        stop_token::StopToken,
+        update_listeners::{channel, test_utils::update, UpdateListenerExt},
+    };
+
+    type Store = StorageDedupStore<InMemStorage<Vec<i32>, String>>;
+
+    async fn receive(window: usize, store: Arc<Store>, ids: &[i32]) -> Vec<i32> {
+        let (tx, listener) = channel(ids.len());
+        for &id in ids {
+            tx.send(update(id)).await.unwrap();
+        }
+
+        let mut listener = listener.dedup(window).store(store);
+        listener.stop_token().stop();
+
+        listener.as_stream().map(|res| res.unwrap().id).collect().await
+    }
+
+    let store = StorageDedupStore::new(InMemStorage::new(), "bot");
+
+    assert_eq!(receive(2, Arc::clone(&store), &[1, 2, 1, 3, 1]).await, [1, 2, 3, 1]);
+    // IDs 3 and 1 were persisted, 2 was evicted from the window.
+    assert_eq!(receive(2, store, &[1, 3, 4]).await, [4]);
+}
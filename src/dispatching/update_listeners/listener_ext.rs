@@ -0,0 +1,497 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+
+use crate::{
+    dispatching::update_listeners::{Acknowledger, AsUpdateStream, Dedup, Record, UpdateListener},
+    types::{AllowedUpdate, Update},
+};
+
+/// Combinators for [`UpdateListener`]s.
+///
+/// All the combinators forward [hints] and [acknowledgements] to the
+/// underlying listeners.
+///
+/// [hints]: UpdateListener::hint_allowed_updates
+/// [acknowledgements]: UpdateListener::acknowledger
+pub trait UpdateListenerExt<E>: UpdateListener<E> + Sized {
+    /// Returns a listener which receives updates from both `self` and `other`.
+    ///
+    /// The returned listener is stopped by stopping both listeners. It ends
+    /// when both listeners end.
+    ///
+    /// An acknowledgement is forwarded only to the listener which produced
+    /// the acknowledged update.
+    fn merge<L>(self, other: L) -> Merge<Self, L>
+    where
+        L: UpdateListener<E>,
+    {
+        Merge { first: self, second: other, sources: None }
+    }
+
+    /// Returns a listener which only returns updates for which `f` returns
+    /// `true`.
+    ///
+    /// Errors are always returned. Filtered out updates are acknowledged right
+    /// away.
+    fn filter<F>(self, f: F) -> Filter<Self, F>
+    where
+        F: FnMut(&Update) -> bool,
+    {
+        Filter { listener: self, f, acknowledger: None }
+    }
+
+    /// Returns a listener which passes every update through `f`.
+    ///
+    /// `f` must not change [`Update::id`], otherwise acknowledgements won't
+    /// work.
+    fn map<F>(self, f: F) -> Map<Self, F>
+    where
+        F: FnMut(Update) -> Update,
+    {
+        Map { listener: self, f }
+    }
+
+    /// Returns a listener which calls `f` with a reference to every update,
+    /// e.g. to log it.
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        F: FnMut(&Update),
+    {
+        Inspect { listener: self, f }
+    }
+
+    /// Returns a listener which passes every error through `f`.
+    ///
+    /// This is useful, e.g., to [`merge`] listeners with different error
+    /// types.
+    ///
+    /// [`merge`]: UpdateListenerExt::merge
+    fn map_err<F, E2>(self, f: F) -> MapErr<Self, F, E>
+    where
+        F: FnMut(E) -> E2,
+    {
+        MapErr { listener: self, f, phantom: PhantomData }
+    }
+
+    /// Returns a listener which drops updates whose IDs are among the last
+    /// `window` seen IDs.
+    ///
+    /// This protects from updates which are delivered multiple times, e.g.
+    /// because of webhook retries or because the same update is returned from
+    /// [merged] listeners. To also drop duplicates after a restart, use
+    /// [`Dedup::store`].
+    ///
+    /// An update is considered seen as soon as it's returned from the
+    /// listener, so its duplicates are dropped even if handling of the update
+    /// fails.
+    ///
+    /// [merged]: UpdateListenerExt::merge
+    fn dedup(self, window: usize) -> Dedup<Self> {
+        Dedup::new(self, window)
+    }
+
+    /// Returns a listener which appends every update to a [JSON Lines] file at
+    /// `path`, along with the time when it was received.
+    ///
+    /// The file is opened (or created) when the first update is received.
+    /// Errors of recording are logged and don't affect the returned updates.
+    ///
+    /// The recorded updates can be fed to a dispatcher again with [`replay`].
+    ///
+    /// [JSON Lines]: https://jsonlines.org
+    /// [`replay`]: crate::dispatching::update_listeners::replay
+    fn record(self, path: impl Into<PathBuf>) -> Record<Self> {
+        Record::new(self, path.into())
+    }
+}
+
+impl<L, E> UpdateListenerExt<E> for L where L: UpdateListener<E> {}
+
+/// A listener returned from [`UpdateListenerExt::merge`].
+pub struct Merge<L1, L2> {
+    first: L1,
+    second: L2,
+    /// Which listeners produced unacknowledged updates, if the updates are
+    /// acknowledged.
+    sources: Option<Arc<Mutex<Sources>>>,
+}
+
+/// Listeners which produced unacknowledged updates, by IDs of the updates.
+///
+/// The same update may be produced by both listeners, so there is a queue of
+/// listeners for every ID.
+type Sources = HashMap<i32, VecDeque<Side>>;
+
+/// One of the [`Merge`]d listeners.
+#[derive(Clone, Copy)]
+enum Side {
+    First,
+    Second,
+}
+
+/// Records that updates of `stream` were produced by the `side` listener.
+fn tag<'a, S, E>(
+    stream: S,
+    sources: Option<Arc<Mutex<Sources>>>,
+    side: Side,
+) -> BoxStream<'a, Result<Update, E>>
+where
+    S: Stream<Item = Result<Update, E>> + Send + 'a,
+{
+    Box::pin(stream.inspect(move |res| {
+        if let (Ok(upd), Some(sources)) = (res, &sources) {
+            let mut sources = sources.lock().expect("the lock is never poisoned");
+            sources.entry(upd.id).or_default().push_back(side);
+        }
+    }))
+}
+
+impl<'a, L1, L2, E> AsUpdateStream<'a, E> for Merge<L1, L2>
+where
+    L1: AsUpdateStream<'a, E>,
+    L2: AsUpdateStream<'a, E>,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let first = tag(self.first.as_stream(), self.sources.clone(), Side::First);
+        let second = tag(self.second.as_stream(), self.sources.clone(), Side::Second);
+
+        Box::pin(stream::select(first, second))
+    }
+}
+
+impl<L1, L2, E> UpdateListener<E> for Merge<L1, L2>
+where
+    Self: for<'a> AsUpdateStream<'a, E>,
+    L1: UpdateListener<E>,
+    L2: UpdateListener<E>,
+{
+    type StopToken = (L1::StopToken, L2::StopToken);
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        (self.first.stop_token(), self.second.stop_token())
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        let hint: Vec<_> = hint.collect();
+
+        self.first.hint_allowed_updates(&mut hint.iter().cloned());
+        self.second.hint_allowed_updates(&mut hint.into_iter());
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        // Check for a shutdown as often as either of the listeners needs.
+        Some(self.first.timeout_hint()?.min(self.second.timeout_hint()?))
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        let first = self.first.acknowledger();
+        let second = self.second.acknowledger();
+        if first.is_none() && second.is_none() {
+            return None;
+        }
+
+        let sources = Arc::new(Mutex::new(Sources::new()));
+        self.sources = Some(Arc::clone(&sources));
+
+        Some(Acknowledger::new(move |update_id| {
+            let side = {
+                let mut sources = sources.lock().expect("the lock is never poisoned");
+                match sources.get_mut(&update_id) {
+                    Some(sides) => {
+                        let side = sides.pop_front();
+                        if sides.is_empty() {
+                            sources.remove(&update_id);
+                        }
+
+                        side
+                    }
+                    None => None,
+                }
+            };
+
+            let ack = match side {
+                Some(Side::First) => first.as_ref().map(|first| first.ack(update_id)),
+                Some(Side::Second) => second.as_ref().map(|second| second.ack(update_id)),
+                None => {
+                    log::warn!("An unknown update #{} has been acknowledged", update_id);
+                    None
+                }
+            };
+
+            async move {
+                if let Some(ack) = ack {
+                    ack.await;
+                }
+            }
+        }))
+    }
+}
+
+/// A listener returned from [`UpdateListenerExt::filter`].
+pub struct Filter<L, F> {
+    listener: L,
+    f: F,
+    acknowledger: Option<Acknowledger>,
+}
+
+impl<'a, L, F, E> AsUpdateStream<'a, E> for Filter<L, F>
+where
+    L: AsUpdateStream<'a, E>,
+    F: FnMut(&Update) -> bool + Send + 'a,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let f = &mut self.f;
+        let acknowledger = self.acknowledger.clone();
+
+        Box::pin(self.listener.as_stream().filter(move |res| {
+            let ack = match res {
+                Ok(upd) if !f(upd) => Some(acknowledger.as_ref().map(|a| a.ack(upd.id))),
+                _ => None,
+            };
+
+            async move {
+                match ack {
+                    Some(ack) => {
+                        if let Some(ack) = ack {
+                            ack.await;
+                        }
+                        false
+                    }
+                    None => true,
+                }
+            }
+        }))
+    }
+}
+
+impl<L, F, E> UpdateListener<E> for Filter<L, F>
+where
+    Self: for<'a> AsUpdateStream<'a, E>,
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.listener.hint_allowed_updates(hint)
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        self.acknowledger = self.listener.acknowledger();
+        self.acknowledger.clone()
+    }
+}
+
+/// A listener returned from [`UpdateListenerExt::map`].
+pub struct Map<L, F> {
+    listener: L,
+    f: F,
+}
+
+impl<'a, L, F, E> AsUpdateStream<'a, E> for Map<L, F>
+where
+    L: AsUpdateStream<'a, E>,
+    F: FnMut(Update) -> Update + Send + 'a,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let f = &mut self.f;
+        Box::pin(self.listener.as_stream().map(move |res| res.map(&mut *f)))
+    }
+}
+
+/// A listener returned from [`UpdateListenerExt::inspect`].
+pub struct Inspect<L, F> {
+    listener: L,
+    f: F,
+}
+
+impl<'a, L, F, E> AsUpdateStream<'a, E> for Inspect<L, F>
+where
+    L: AsUpdateStream<'a, E>,
+    F: FnMut(&Update) + Send + 'a,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let f = &mut self.f;
+        Box::pin(self.listener.as_stream().inspect(move |res| {
+            if let Ok(upd) = res {
+                f(upd);
+            }
+        }))
+    }
+}
+
+/// A listener returned from [`UpdateListenerExt::map_err`].
+pub struct MapErr<L, F, E> {
+    listener: L,
+    f: F,
+    phantom: PhantomData<fn(E)>,
+}
+
+impl<'a, L, F, E, E2> AsUpdateStream<'a, E2> for MapErr<L, F, E>
+where
+    L: AsUpdateStream<'a, E>,
+    F: FnMut(E) -> E2 + Send + 'a,
+{
+    type Stream = BoxStream<'a, Result<Update, E2>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let f = &mut self.f;
+        Box::pin(self.listener.as_stream().map(move |res| res.map_err(&mut *f)))
+    }
+}
+
+impl<L, F, E, E2> UpdateListener<E2> for MapErr<L, F, E>
+where
+    Self: for<'a> AsUpdateStream<'a, E2>,
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.listener.hint_allowed_updates(hint)
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        self.listener.acknowledger()
+    }
+}
+
+/// Implements [`UpdateListener`] for wrappers which only change the stream.
+macro_rules! forward_update_listener {
+    ($($ty:ident),*) => {
+        $(
+            impl<L, F, E> UpdateListener<E> for $ty<L, F>
+            where
+                Self: for<'a> AsUpdateStream<'a, E>,
+                L: UpdateListener<E>,
+            {
+                type StopToken = L::StopToken;
+
+                fn stop_token(&mut self) -> Self::StopToken {
+                    self.listener.stop_token()
+                }
+
+                fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+                    self.listener.hint_allowed_updates(hint)
+                }
+
+                fn timeout_hint(&self) -> Option<Duration> {
+                    self.listener.timeout_hint()
+                }
+
+                fn acknowledger(&mut self) -> Option<Acknowledger> {
+                    self.listener.acknowledger()
+                }
+            }
+        )*
+    };
+}
+
+forward_update_listener!(Map, Inspect);
+
+#[tokio::test]
+async fn combinators() {
+    use std::convert::Infallible;
+
+    use crate::dispatching::update_listeners::{test_utils::update, StatefulListener};
+
+    fn listener(ids: Vec<i32>) -> impl UpdateListener<Infallible> {
+        let updates = ids.into_iter().map(|id| Ok(update(id)));
+
+        StatefulListener::from_stream_without_graceful_shutdown(stream::iter(updates))
+    }
+
+    let mut listener = listener(vec![1, 2, 3])
+        .merge(listener(vec![4]))
+        .filter(|upd| upd.id != 2)
+        .map_err(|err: Infallible| err.to_string());
+
+    let mut ids: Vec<_> = listener.as_stream().map(|res| res.unwrap().id).collect().await;
+    ids.sort_unstable();
+
+    assert_eq!(ids, [1, 3, 4]);
+}
+
+#[tokio::test]
+async fn merge_forwards_acknowledgements_to_the_source() {
+    use std::convert::Infallible;
+
+    use crate::dispatching::{stop_token::Noop, update_listeners::test_utils::update};
+
+    struct Acking {
+        ids: Vec<i32>,
+        acked: Arc<Mutex<Vec<i32>>>,
+    }
+
+    impl<'a> AsUpdateStream<'a, Infallible> for Acking {
+        type Stream = BoxStream<'a, Result<Update, Infallible>>;
+
+        fn as_stream(&'a mut self) -> Self::Stream {
+            Box::pin(stream::iter(self.ids.clone()).map(|id| Ok(update(id))))
+        }
+    }
+
+    impl UpdateListener<Infallible> for Acking {
+        type StopToken = Noop;
+
+        fn stop_token(&mut self) -> Self::StopToken {
+            Noop
+        }
+
+        fn acknowledger(&mut self) -> Option<Acknowledger> {
+            let acked = Arc::clone(&self.acked);
+
+            Some(Acknowledger::new(move |update_id| {
+                acked.lock().unwrap().push(update_id);
+                async {}
+            }))
+        }
+    }
+
+    let first = Arc::new(Mutex::new(Vec::new()));
+    let second = Arc::new(Mutex::new(Vec::new()));
+
+    let mut listener = Acking { ids: vec![1, 2], acked: Arc::clone(&first) }
+        .merge(Acking { ids: vec![2, 3], acked: Arc::clone(&second) });
+    let acknowledger = listener.acknowledger().unwrap();
+
+    let ids: Vec<_> = listener.as_stream().map(|res| res.unwrap().id).collect().await;
+    for id in ids {
+        acknowledger.ack(id).await;
+    }
+
+    first.lock().unwrap().sort_unstable();
+    second.lock().unwrap().sort_unstable();
+    assert_eq!(*first.lock().unwrap(), [1, 2]);
+    assert_eq!(*second.lock().unwrap(), [2, 3]);
+}
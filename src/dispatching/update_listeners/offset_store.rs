@@ -0,0 +1,188 @@
+use std::{
+    fmt::{Debug, Display},
+    io,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{dispatching::dialogue::Storage, types::Update};
+
+/// A storage of the polling offset.
+///
+/// Allows [`polling`] to resume from the last handled update after a restart,
+/// see [`PollingBuilder::offset_store`].
+///
+/// Along with the offset, a store keeps updates which were received, but not
+/// handled yet (see [`SavedOffset`]), because Telegram doesn't send them
+/// again once they are confirmed by the offset.
+///
+/// Currently we support the following stores out of the box:
+///
+/// - [`FileOffsetStore`] -- keeps the offset in a file.
+/// - [`StorageOffsetStore`] -- keeps the offset in a dialogue [`Storage`], e.g.
+///   [`SqliteStorage`] or [`RedisStorage`].
+///
+/// [`polling`]: crate::dispatching::update_listeners::polling()
+/// [`PollingBuilder::offset_store`]: crate::dispatching::update_listeners::PollingBuilder::offset_store
+/// [`SqliteStorage`]: crate::dispatching::dialogue::SqliteStorage
+/// [`RedisStorage`]: crate::dispatching::dialogue::RedisStorage
+pub trait OffsetStore {
+    type Error;
+
+    /// Returns the saved offset, if any.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn load_offset(self: Arc<Self>)
+        -> BoxFuture<'static, Result<Option<SavedOffset>, Self::Error>>;
+
+    /// Saves `offset`, replacing the previously saved one.
+    #[must_use = "Futures are lazy and do nothing unless polled with .await"]
+    fn save_offset(
+        self: Arc<Self>,
+        offset: SavedOffset,
+    ) -> BoxFuture<'static, Result<(), Self::Error>>;
+}
+
+/// What an [`OffsetStore`] keeps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SavedOffset {
+    /// An ID of the first update which hasn't been received yet.
+    pub offset: i32,
+
+    /// Updates which have been received, but haven't been handled yet.
+    ///
+    /// They are returned from the listener again after a restart. There are
+    /// at most 100 of them: the listener doesn't receive new updates until
+    /// older ones are handled.
+    pub unhandled: Vec<Update>,
+}
+
+/// An offset store which keeps the offset in a file (as JSON).
+///
+/// The offset is first written to a temporary file next to `path`, which then
+/// replaces the file at `path`, so the offset isn't corrupted if the bot
+/// crashes during writing.
+#[derive(Debug)]
+pub struct FileOffsetStore {
+    path: PathBuf,
+}
+
+impl FileOffsetStore {
+    #[must_use]
+    pub fn new(path: impl Into<PathBuf>) -> Arc<Self> {
+        Arc::new(Self { path: path.into() })
+    }
+}
+
+impl OffsetStore for FileOffsetStore {
+    type Error = io::Error;
+
+    fn load_offset(
+        self: Arc<Self>,
+    ) -> BoxFuture<'static, Result<Option<SavedOffset>, Self::Error>> {
+        Box::pin(async move {
+            match tokio::fs::read(&self.path).await {
+                Ok(offset) => serde_json::from_slice(&offset)
+                    .map(Some)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+    }
+
+    fn save_offset(
+        self: Arc<Self>,
+        offset: SavedOffset,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Box::pin(async move {
+            let mut tmp_path = self.path.clone().into_os_string();
+            tmp_path.push(".tmp");
+
+            let offset = serde_json::to_vec(&offset)?;
+            tokio::fs::write(&tmp_path, offset).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        })
+    }
+}
+
+/// An offset store which keeps the offset in a dialogue [`Storage`].
+///
+/// The offset is kept under the key `teloxide_polling_offset:{name}`, so it
+/// doesn't clash with dialogues, which are indexed by chat IDs, even if they
+/// share the storage. Use different `name`s for different bots.
+///
+/// Since [`SavedOffset`] contains updates, the storage must use a serializer
+/// which supports them, e.g. [`Json`].
+///
+/// [`Json`]: crate::dispatching::dialogue::serializer::Json
+pub struct StorageOffsetStore<S> {
+    storage: Arc<S>,
+    key: String,
+}
+
+impl<S> StorageOffsetStore<S> {
+    #[must_use]
+    pub fn new(storage: Arc<S>, name: impl Display) -> Arc<Self> {
+        Arc::new(Self { storage, key: format!("{}{}", KEY_PREFIX, name) })
+    }
+}
+
+/// A prefix of keys of [`StorageOffsetStore`].
+const KEY_PREFIX: &str = "teloxide_polling_offset:";
+
+impl<S> OffsetStore for StorageOffsetStore<S>
+where
+    S: Storage<SavedOffset, String>,
+{
+    type Error = S::Error;
+
+    fn load_offset(
+        self: Arc<Self>,
+    ) -> BoxFuture<'static, Result<Option<SavedOffset>, Self::Error>> {
+        Arc::clone(&self.storage).get_dialogue(self.key.clone())
+    }
+
+    fn save_offset(
+        self: Arc<Self>,
+        offset: SavedOffset,
+    ) -> BoxFuture<'static, Result<(), Self::Error>> {
+        Arc::clone(&self.storage).update_dialogue(self.key.clone(), offset)
+    }
+}
+
+/// An [`OffsetStore`] which logs errors instead of returning them.
+pub(crate) trait LoggingOffsetStore: Send + Sync {
+    fn load(self: Arc<Self>) -> BoxFuture<'static, Option<SavedOffset>>;
+
+    fn save(self: Arc<Self>, offset: SavedOffset) -> BoxFuture<'static, ()>;
+}
+
+impl<S> LoggingOffsetStore for S
+where
+    S: OffsetStore + Send + Sync + 'static,
+    S::Error: Debug,
+{
+    fn load(self: Arc<Self>) -> BoxFuture<'static, Option<SavedOffset>> {
+        let fut = self.load_offset();
+
+        Box::pin(async move {
+            fut.await.unwrap_or_else(|err| {
+                log::error!("Failed to load the polling offset: {:?}", err);
+                None
+            })
+        })
+    }
+
+    fn save(self: Arc<Self>, offset: SavedOffset) -> BoxFuture<'static, ()> {
+        let fut = self.save_offset(offset);
+
+        Box::pin(async move {
+            if let Err(err) = fut.await {
+                log::error!("Failed to save the polling offset: {:?}", err);
+            }
+        })
+    }
+}
@@ -1,23 +1,34 @@
-use std::{convert::TryInto, time::Duration};
-
-use futures::{
-    future::{ready, Either},
-    stream::{self, Stream, StreamExt},
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    convert::{TryFrom, TryInto},
+    fmt::Debug,
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use crate::{
     dispatching::{
         stop_token::{AsyncStopFlag, AsyncStopToken, StopToken},
-        update_listeners::{stateful_listener::StatefulListener, UpdateListener},
+        update_listeners::{
+            offset_store::LoggingOffsetStore, stateful_listener::StatefulListener, Acknowledger,
+            AsUpdateStream, ListenerStats, OffsetStore, SavedOffset, UpdateListener,
+        },
     },
     payloads::{GetUpdates, GetUpdatesSetters as _},
     requests::{HasPayload, Request, Requester},
     types::{AllowedUpdate, Update},
+    ApiError, RequestError,
 };
+use futures::{
+    future::{self, ready, Either},
+    stream::{self, Stream, StreamExt},
+};
+use tokio::sync::Notify;
 
 /// Returns a long polling update listener with `timeout` of 10 seconds.
 ///
-/// See also: [`polling`](polling).
+/// See also: [`polling`](polling), [`PollingBuilder`].
 ///
 /// ## Notes
 ///
@@ -28,9 +39,9 @@ pub async fn polling_default<R>(
 where
     R: Requester + Send + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: PollingError,
 {
-    delete_webhook_if_setup(&requester).await;
-    polling(requester, Some(Duration::from_secs(10)), None, None)
+    PollingBuilder::new(requester).timeout(Duration::from_secs(10)).delete_webhook(true).build()
 }
 
 #[cfg_attr(doc, aquamarine::aquamarine)]
@@ -44,6 +55,9 @@ where
 ///
 /// See [`GetUpdates`] for defaults.
 ///
+/// Failed requests are retried with the default [`Backoff`], use
+/// [`PollingBuilder`] to configure it.
+///
 /// See also: [`polling_default`](polling_default).
 ///
 /// ## Notes
@@ -134,97 +148,708 @@ pub fn polling<R>(
 where
     R: Requester + Send + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: PollingError,
 {
-    struct State<B: Requester> {
-        bot: B,
-        timeout: Option<u32>,
-        limit: Option<u8>,
-        allowed_updates: Option<Vec<AllowedUpdate>>,
-        offset: i32,
-        flag: AsyncStopFlag,
-        token: AsyncStopToken,
-        force_stop: bool,
-    }
-
-    fn stream<B>(st: &mut State<B>) -> impl Stream<Item = Result<Update, B::Err>> + Send + '_
+    PollingBuilder { timeout, limit, allowed_updates, ..PollingBuilder::new(bot) }.build()
+}
+
+/// A builder of a long polling update listener.
+///
+/// By default, no `timeout`, `limit` and `allowed_updates` are sent (see
+/// [`GetUpdates`] for their defaults) and the [`Backoff::default`] is used.
+///
+/// See [`polling`] for more.
+///
+/// [`polling`]: polling()
+#[must_use]
+pub struct PollingBuilder<R> {
+    bot: R,
+    timeout: Option<Duration>,
+    limit: Option<u8>,
+    allowed_updates: Option<Vec<AllowedUpdate>>,
+    backoff: Backoff,
+    offset_store: Option<Arc<dyn LoggingOffsetStore>>,
+    drop_pending_updates: bool,
+    delete_webhook: bool,
+    stats: Option<ListenerStats>,
+}
+
+impl<R> PollingBuilder<R>
+where
+    R: Requester + Send + 'static,
+    <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: PollingError,
+{
+    /// Creates a new builder which will receive updates using `bot`.
+    pub fn new(bot: R) -> Self {
+        Self {
+            bot,
+            timeout: None,
+            limit: None,
+            allowed_updates: None,
+            backoff: Backoff::default(),
+            offset_store: None,
+            drop_pending_updates: false,
+            delete_webhook: false,
+            stats: None,
+        }
+    }
+
+    /// A timeout for polling.
+    ///
+    /// ## Panics
+    ///
+    /// If `timeout` is bigger than `u32::MAX` seconds.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout: Some(timeout), ..self }
+    }
+
+    /// Limits the number of updates to be retrieved at once. Values between
+    /// 1—100 are accepted.
+    pub fn limit(self, limit: u8) -> Self {
+        Self { limit: Some(limit), ..self }
+    }
+
+    /// A list the types of updates you want to receive.
+    ///
+    /// Note that [`Dispatcher`] may override it using [`hint_allowed_updates`].
+    ///
+    /// [`Dispatcher`]: crate::dispatching::Dispatcher
+    /// [`hint_allowed_updates`]: UpdateListener::hint_allowed_updates
+    pub fn allowed_updates(self, allowed_updates: Vec<AllowedUpdate>) -> Self {
+        Self { allowed_updates: Some(allowed_updates), ..self }
+    }
+
+    /// Delays between failed requests.
+    pub fn backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Drops all updates which are pending when the listener starts.
+    ///
+    /// This is useful, e.g., to ignore messages which were sent while the bot
+    /// was offline. Disabled by default.
+    pub fn drop_pending_updates(self, drop_pending_updates: bool) -> Self {
+        Self { drop_pending_updates, ..self }
+    }
+
+    /// Deletes a webhook, if it was set up, when the listener starts.
+    ///
+    /// Telegram doesn't allow to receive updates via [`get_updates`] while a
+    /// webhook is set up. Disabled by default ([`polling_default`] enables
+    /// it).
+    ///
+    /// [`get_updates`]: crate::requests::Requester::get_updates
+    pub fn delete_webhook(self, delete_webhook: bool) -> Self {
+        Self { delete_webhook, ..self }
+    }
+
+    /// Saves the offset to `store`, so that polling resumes from the first
+    /// unhandled update after a restart.
+    ///
+    /// When the listener starts, it loads the offset from `store`. If the
+    /// consumer of updates acknowledges them (see
+    /// [`UpdateListener::acknowledger`]), updates which haven't been handled
+    /// yet are saved along with the offset (see [`SavedOffset`]), so updates
+    /// which weren't handled because of a crash are returned from the listener
+    /// again after a restart. Otherwise, an update is considered handled once
+    /// it is returned from the listener.
+    ///
+    /// No more than 100 updates are left unhandled at once: while there are
+    /// that many, the listener waits for them to be handled before receiving
+    /// new ones.
+    ///
+    /// The offset is saved before every [`get_updates`] request (which makes
+    /// Telegram forget the previously received updates) and when the listener
+    /// is stopped, so handled updates may be returned again after a crash.
+    ///
+    /// Errors of `store` are logged via [`log::error`].
+    ///
+    /// [`get_updates`]: crate::requests::Requester::get_updates
+    pub fn offset_store<S>(self, store: Arc<S>) -> Self
     where
-        B: Requester + Send,
-        <B as Requester>::GetUpdates: Send,
+        S: OffsetStore + Send + Sync + 'static,
+        S::Error: Debug,
     {
-        stream::unfold(st, move |state| async move {
-            let State { timeout, limit, allowed_updates, bot, offset, flag, force_stop, .. } =
-                &mut *state;
+        Self { offset_store: Some(store), ..self }
+    }
 
-            if *force_stop {
-                return None;
-            }
+    /// Records statistics of the listener to `stats`: the time of the last
+    /// successful [`get_updates`] request, the number of consecutive failed
+    /// requests, etc.
+    ///
+    /// [`get_updates`]: crate::requests::Requester::get_updates
+    pub fn stats(self, stats: ListenerStats) -> Self {
+        Self { stats: Some(stats), ..self }
+    }
 
-            if flag.is_stopped() {
-                let mut req = bot.get_updates().offset(*offset).timeout(0).limit(1);
-                req.payload_mut().allowed_updates = allowed_updates.take();
+    /// Returns a long polling update listener with the specified options.
+    ///
+    /// When a [`get_updates`] request fails, the error is returned from the
+    /// stream and the next request is delayed according to the [`Backoff`]
+    /// (or, if Telegram asked to retry after some time, for that time).
+    ///
+    /// Some errors are fatal, i.e. retrying won't help: an invalid token and a
+    /// conflict with another bot instance which uses the same token. After
+    /// such an error the listener stops. Errors are classified by
+    /// [`PollingError`].
+    ///
+    /// [`get_updates`]: crate::requests::Requester::get_updates
+    pub fn build(self) -> impl UpdateListener<R::Err, StopToken = impl Send + StopToken> {
+        let Self {
+            bot,
+            timeout,
+            limit,
+            allowed_updates,
+            backoff,
+            offset_store,
+            drop_pending_updates,
+            delete_webhook,
+            stats,
+        } = self;
 
-                return match req.send().await {
-                    Ok(_) => None,
-                    Err(err) => {
-                        // Prevents infinite retries, see https://github.com/teloxide/teloxide/issues/496
-                        *force_stop = true;
+        struct State<B: Requester> {
+            bot: B,
+            timeout: Option<u32>,
+            limit: Option<u8>,
+            allowed_updates: Option<Vec<AllowedUpdate>>,
+            offset: i32,
+            flag: AsyncStopFlag,
+            token: AsyncStopToken,
+            force_stop: bool,
+            backoff: Backoff,
+            /// The number of consecutive failed requests.
+            errors: u32,
+            retry_after: Option<Duration>,
+            offsets: Option<Arc<Offsets>>,
+            drop_pending_updates: bool,
+            delete_webhook: bool,
+            started: bool,
+            stats: Option<ListenerStats>,
+        }
 
-                        Some((Either::Left(stream::once(ready(Err(err)))), state))
+        fn stream<B>(st: &mut State<B>) -> impl Stream<Item = Result<Update, B::Err>> + Send + '_
+        where
+            B: Requester + Send + 'static,
+            <B as Requester>::GetUpdates: Send,
+            <B as Requester>::Err: PollingError,
+        {
+            stream::unfold(st, move |state| async move {
+                let State {
+                    timeout,
+                    limit,
+                    allowed_updates,
+                    bot,
+                    offset,
+                    flag,
+                    force_stop,
+                    backoff,
+                    errors,
+                    retry_after,
+                    offsets,
+                    drop_pending_updates,
+                    delete_webhook,
+                    started,
+                    stats,
+                    ..
+                } = &mut *state;
+
+                if *force_stop {
+                    return None;
+                }
+
+                if !*started {
+                    *started = true;
+
+                    if *delete_webhook {
+                        delete_webhook_if_setup(bot).await;
+                    }
+
+                    let unhandled = match offsets {
+                        Some(offsets) => offsets.load().await,
+                        None => Vec::new(),
+                    };
+
+                    if *drop_pending_updates {
+                        if let Some(offsets) = offsets {
+                            offsets.drop_unhandled();
+                        }
+
+                        if let Some(next) = drop_pending(bot).await {
+                            match offsets {
+                                Some(offsets) => offsets.skip_to(next),
+                                None => *offset = next,
+                            }
+                        }
+                    } else if !unhandled.is_empty() {
+                        log::info!("Returning {} updates saved before a restart", unhandled.len());
+
+                        let updates = unhandled.into_iter().map(Ok);
+                        return Some((Either::Right(stream::iter(updates)), state));
+                    }
+                }
+
+                if let Some(offsets) = offsets {
+                    // Unhandled updates are saved along with the offset, so their number is
+                    // limited.
+                    while offsets.capacity() == 0 && !flag.is_stopped() {
+                        let acked = offsets.acked.notified();
+                        tokio::pin!(acked);
+
+                        future::select(acked, &mut *flag).await;
+                    }
+
+                    // Unhandled updates must be saved before Telegram is asked to forget them.
+                    *offset = offsets.next();
+                    offsets.save().await;
+                }
+
+                if let Some(delay) = retry_after.take() {
+                    let sleep = tokio::time::sleep(delay);
+                    tokio::pin!(sleep);
+
+                    // Wake up early if the listener is stopped.
+                    future::select(sleep, &mut *flag).await;
+                }
+
+                if flag.is_stopped() {
+                    if let Some(offsets) = offsets {
+                        offsets.finish().await;
                     }
+
+                    let mut req = bot.get_updates().offset(*offset).timeout(0).limit(1);
+                    req.payload_mut().allowed_updates = allowed_updates.take();
+
+                    return match req.send().await {
+                        Ok(_) => None,
+                        Err(err) => {
+                            // Prevents infinite retries, see https://github.com/teloxide/teloxide/issues/496
+                            *force_stop = true;
+
+                            Some((Either::Left(stream::once(ready(Err(err)))), state))
+                        }
+                    };
+                }
+
+                let limit = match offsets {
+                    Some(offsets) => offsets.limit(*limit),
+                    None => *limit,
                 };
-            }
 
-            let mut req = bot.get_updates();
-            *req.payload_mut() = GetUpdates {
-                offset: Some(*offset),
-                timeout: *timeout,
-                limit: *limit,
-                allowed_updates: allowed_updates.take(),
-            };
+                let mut req = bot.get_updates();
+                *req.payload_mut() = GetUpdates {
+                    offset: Some(*offset),
+                    timeout: *timeout,
+                    limit,
+                    allowed_updates: allowed_updates.take(),
+                };
+
+                match req.send().await {
+                    Ok(updates) => {
+                        *errors = 0;
+                        if let Some(stats) = stats {
+                            stats.record_success(&updates);
+                        }
 
-            match req.send().await {
-                Ok(updates) => {
-                    // Set offset to the last update's id + 1
-                    if let Some(upd) = updates.last() {
-                        *offset = upd.id + 1;
+                        let updates = match offsets {
+                            Some(offsets) => offsets.receive(updates),
+                            None => {
+                                // Set offset to the last update's id + 1
+                                if let Some(upd) = updates.last() {
+                                    *offset = upd.id + 1;
+                                }
+
+                                updates
+                            }
+                        };
+
+                        let updates = updates.into_iter().map(Ok);
+                        Some((Either::Right(stream::iter(updates)), state))
                     }
+                    Err(err) => {
+                        *errors = errors.saturating_add(1);
+                        if let Some(stats) = stats {
+                            stats.record_error();
+                        }
 
-                    let updates = updates.into_iter().map(Ok);
-                    Some((Either::Right(stream::iter(updates)), state))
+                        match err.kind() {
+                            PollingErrorKind::Fatal => {
+                                log::error!("Polling has been stopped because of a fatal error");
+                                *force_stop = true;
+                            }
+                            PollingErrorKind::RetryAfter(delay) => *retry_after = Some(delay),
+                            PollingErrorKind::Other => *retry_after = Some(backoff.delay(*errors)),
+                        }
+
+                        Some((Either::Left(stream::once(ready(Err(err)))), state))
+                    }
+                }
+            })
+            .flatten()
+        }
+
+        let (token, flag) = AsyncStopToken::new_pair();
+
+        let state = State {
+            bot,
+            timeout: timeout.map(|t| t.as_secs().try_into().expect("timeout is too big")),
+            limit,
+            allowed_updates,
+            offset: 0,
+            flag,
+            token,
+            force_stop: false,
+            backoff,
+            errors: 0,
+            retry_after: None,
+            offsets: offset_store.map(|store| Arc::new(Offsets::new(store))),
+            drop_pending_updates,
+            delete_webhook,
+            started: false,
+            stats,
+        };
+
+        let stop_token = |st: &mut State<_>| st.token.clone();
+
+        let hint_allowed_updates =
+            Some(|state: &mut State<_>, allowed: &mut dyn Iterator<Item = AllowedUpdate>| {
+                // TODO: we should probably warn if there already were different allowed updates
+                // before
+                state.allowed_updates = Some(allowed.collect());
+            });
+        let timeout_hint = Some(move |_: &State<_>| timeout);
+
+        let offsets = state.offsets.clone();
+        let listener = StatefulListener::new_with_hints(
+            state,
+            stream,
+            stop_token,
+            hint_allowed_updates,
+            timeout_hint,
+        );
+
+        PollingListener { listener, offsets }
+    }
+}
+
+/// The maximum number of unacknowledged updates, which are saved along with
+/// the offset.
+const MAX_UNHANDLED_UPDATES: usize = 100;
+
+/// Offsets of a polling listener with an [`OffsetStore`].
+struct Offsets {
+    store: Arc<dyn LoggingOffsetStore>,
+    state: Mutex<OffsetsState>,
+    /// Notified when an update is acknowledged.
+    acked: Notify,
+    /// The version of [`OffsetsState`] which was saved last. The lock is held
+    /// while saving, so that offsets are saved in order.
+    saved: tokio::sync::Mutex<u64>,
+}
+
+struct OffsetsState {
+    /// Whether the consumer of updates acknowledges them.
+    acks: bool,
+    /// Updates which were returned from the listener, but haven't been
+    /// acknowledged yet, by their IDs.
+    unacked: BTreeMap<i32, Update>,
+    /// The ID after the last update returned from the listener.
+    next: i32,
+    /// Incremented on every change, so that unchanged offsets aren't saved
+    /// again.
+    version: u64,
+    /// Whether the listener has been stopped, so acknowledgements must be
+    /// saved right away.
+    finished: bool,
+}
+
+impl Offsets {
+    fn new(store: Arc<dyn LoggingOffsetStore>) -> Self {
+        Self {
+            store,
+            state: Mutex::new(OffsetsState {
+                acks: false,
+                unacked: BTreeMap::new(),
+                next: 0,
+                version: 0,
+                finished: false,
+            }),
+            acked: Notify::new(),
+            saved: tokio::sync::Mutex::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OffsetsState> {
+        self.state.lock().expect("the lock is never poisoned")
+    }
+
+    /// Loads the saved offset and returns updates which weren't handled before
+    /// a restart.
+    async fn load(&self) -> Vec<Update> {
+        let SavedOffset { offset, unhandled } = match Arc::clone(&self.store).load().await {
+            Some(saved) => saved,
+            None => return Vec::new(),
+        };
+
+        let mut state = self.lock();
+        state.next = state.next.max(offset);
+        if state.acks {
+            state.unacked.extend(unhandled.iter().map(|upd| (upd.id, upd.clone())));
+        }
+
+        unhandled
+    }
+
+    /// Forgets updates which weren't handled before a restart.
+    fn drop_unhandled(&self) {
+        let mut state = self.lock();
+        state.unacked.clear();
+        state.version += 1;
+    }
+
+    /// Skips all updates with IDs less than `next`.
+    fn skip_to(&self, next: i32) {
+        let mut state = self.lock();
+        state.next = state.next.max(next);
+        state.version += 1;
+    }
+
+    /// Returns an ID of the first update which hasn't been received yet.
+    fn next(&self) -> i32 {
+        self.lock().next
+    }
+
+    /// Returns how many more updates can be received without exceeding
+    /// [`MAX_UNHANDLED_UPDATES`].
+    fn capacity(&self) -> usize {
+        MAX_UNHANDLED_UPDATES.saturating_sub(self.lock().unacked.len())
+    }
+
+    /// Limits the number of updates retrieved at once by [`Self::capacity`].
+    fn limit(&self, limit: Option<u8>) -> Option<u8> {
+        let capacity = u8::try_from(self.capacity()).unwrap_or(u8::MAX).max(1);
+
+        match limit {
+            Some(limit) => Some(limit.min(capacity)),
+            // Telegram retrieves 100 updates by default.
+            None if capacity < 100 => Some(capacity),
+            None => None,
+        }
+    }
+
+    /// Filters out updates which have already been returned from the listener
+    /// and remembers the rest as unacknowledged.
+    fn receive(&self, updates: Vec<Update>) -> Vec<Update> {
+        let mut state = self.lock();
+
+        let next = state.next;
+        let updates: Vec<_> = updates.into_iter().filter(|upd| upd.id >= next).collect();
+
+        if let Some(upd) = updates.last() {
+            state.next = upd.id + 1;
+            state.version += 1;
+        }
+        if state.acks {
+            state.unacked.extend(updates.iter().map(|upd| (upd.id, upd.clone())));
+        }
+
+        updates
+    }
+
+    fn acknowledger(self: Arc<Self>) -> Acknowledger {
+        self.lock().acks = true;
+
+        Acknowledger::new(move |update_id| {
+            let this = Arc::clone(&self);
+
+            async move {
+                let finished = {
+                    let mut state = this.lock();
+                    state.unacked.remove(&update_id);
+                    state.version += 1;
+                    state.finished
+                };
+                this.acked.notify_one();
+
+                // Otherwise, acknowledgements are saved in batches, before the next request.
+                if finished {
+                    this.save().await;
                 }
-                Err(err) => Some((Either::Left(stream::once(ready(Err(err)))), state)),
             }
         })
-        .flatten()
     }
 
-    let (token, flag) = AsyncStopToken::new_pair();
+    /// Saves the current offset and saves acknowledgements right away from now
+    /// on.
+    async fn finish(&self) {
+        self.lock().finished = true;
+        self.save().await;
+    }
 
-    let state = State {
-        bot,
-        timeout: timeout.map(|t| t.as_secs().try_into().expect("timeout is too big")),
-        limit,
-        allowed_updates,
-        offset: 0,
-        flag,
-        token,
-        force_stop: false,
-    };
+    /// Saves the offset along with unacknowledged updates, if they have
+    /// changed.
+    async fn save(&self) {
+        let mut saved = self.saved.lock().await;
+
+        let (version, offset) = {
+            let state = self.lock();
+            let offset = SavedOffset {
+                offset: state.next,
+                unhandled: state.unacked.values().cloned().collect(),
+            };
+
+            (state.version, offset)
+        };
+
+        if *saved != version {
+            Arc::clone(&self.store).save(offset).await;
+            *saved = version;
+        }
+    }
+}
 
-    let stop_token = |st: &mut State<_>| st.token.clone();
+/// A polling listener, which supports acknowledgements if it has an
+/// [`OffsetStore`].
+struct PollingListener<L> {
+    listener: L,
+    offsets: Option<Arc<Offsets>>,
+}
 
-    let hint_allowed_updates =
-        Some(|state: &mut State<_>, allowed: &mut dyn Iterator<Item = AllowedUpdate>| {
-            // TODO: we should probably warn if there already were different allowed updates
-            // before
-            state.allowed_updates = Some(allowed.collect());
-        });
-    let timeout_hint = Some(move |_: &State<_>| timeout);
+impl<'a, L, E> AsUpdateStream<'a, E> for PollingListener<L>
+where
+    L: AsUpdateStream<'a, E>,
+{
+    type Stream = L::Stream;
 
-    StatefulListener::new_with_hints(state, stream, stop_token, hint_allowed_updates, timeout_hint)
+    fn as_stream(&'a mut self) -> Self::Stream {
+        self.listener.as_stream()
+    }
 }
 
-async fn delete_webhook_if_setup<R>(requester: &R)
+impl<L, E> UpdateListener<E> for PollingListener<L>
+where
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.listener.hint_allowed_updates(hint)
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        self.offsets.clone().map(Offsets::acknowledger)
+    }
+}
+
+/// Delays between failed requests of a polling update listener.
+///
+/// After `n` consecutive failed requests, the next request is delayed for
+/// `initial * 2^(n - 1)`, but no more than `max`. With jitter enabled, the
+/// delay is randomly chosen between a half and the whole of that value, so
+/// that several bot instances don't retry at the same time.
+///
+/// The default backoff starts with 1 second, is capped at 30 seconds and uses
+/// jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: bool,
+}
+
+impl Backoff {
+    /// Creates a new backoff which starts with `initial` and is capped at
+    /// `max`, with jitter enabled.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, jitter: true }
+    }
+
+    /// Creates a backoff which doesn't delay requests at all.
+    pub fn none() -> Self {
+        Self { initial: Duration::ZERO, max: Duration::ZERO, jitter: false }
+    }
+
+    /// Enables or disables jitter.
+    #[must_use]
+    pub fn jitter(self, jitter: bool) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// Returns a delay after `errors` consecutive failed requests.
+    pub(crate) fn delay(&self, errors: u32) -> Duration {
+        let factor = 2u32.saturating_pow(errors.saturating_sub(1));
+        let delay = self.initial.saturating_mul(factor).min(self.max);
+
+        if !self.jitter {
+            return delay;
+        }
+
+        let half = delay / 2;
+        let nanos = u64::try_from(half.as_nanos()).unwrap_or(u64::MAX);
+        half + Duration::from_nanos(random_u64() % nanos.saturating_add(1))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+/// An error of a [`get_updates`] request, which tells a polling listener how
+/// to proceed.
+///
+/// It is implemented for [`RequestError`], so it only needs to be implemented
+/// for errors of custom [`Requester`]s.
+///
+/// [`get_updates`]: crate::requests::Requester::get_updates
+pub trait PollingError {
+    /// Returns what kind of error this is.
+    fn kind(&self) -> PollingErrorKind;
+}
+
+/// A kind of a [`PollingError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PollingErrorKind {
+    /// Retrying won't help, so polling stops.
+    Fatal,
+
+    /// Telegram asked to retry after this delay.
+    RetryAfter(Duration),
+
+    /// The request is retried according to the [`Backoff`].
+    Other,
+}
+
+impl PollingError for RequestError {
+    fn kind(&self) -> PollingErrorKind {
+        match self {
+            RequestError::Api(ApiError::InvalidToken)
+            | RequestError::Api(ApiError::TerminatedByOtherGetUpdates) => PollingErrorKind::Fatal,
+            RequestError::RetryAfter(secs) => {
+                PollingErrorKind::RetryAfter(Duration::from_secs((*secs).max(0) as u64))
+            }
+            _ => PollingErrorKind::Other,
+        }
+    }
+}
+
+/// Returns a random number, without depending on a random number generator.
+fn random_u64() -> u64 {
+    // `RandomState` is seeded with random keys.
+    RandomState::new().build_hasher().finish()
+}
+
+async fn delete_webhook_if_setup<R>(requester: &mut R)
 where
     R: Requester,
 {
@@ -245,6 +870,23 @@ where
     }
 }
 
+/// Makes Telegram forget all pending updates and returns an offset after the
+/// last of them.
+async fn drop_pending<R>(requester: &mut R) -> Option<i32>
+where
+    R: Requester,
+{
+    // A negative offset makes Telegram forget all the updates except the last
+    // one.
+    match requester.get_updates().offset(-1).limit(1).timeout(0).send().await {
+        Ok(updates) => updates.last().map(|upd| upd.id + 1),
+        Err(err) => {
+            log::error!("Failed to drop pending updates: {:?}", err);
+            None
+        }
+    }
+}
+
 #[test]
 fn polling_is_send() {
     use crate::dispatching::update_listeners::AsUpdateStream;
@@ -258,3 +900,79 @@ fn polling_is_send() {
 
     fn assert_send(_: &impl Send) {}
 }
+
+#[test]
+fn request_errors_are_classified() {
+    assert_eq!(RequestError::Api(ApiError::InvalidToken).kind(), PollingErrorKind::Fatal);
+    assert_eq!(
+        RequestError::RetryAfter(5).kind(),
+        PollingErrorKind::RetryAfter(Duration::from_secs(5))
+    );
+    assert_eq!(RequestError::Api(ApiError::BotBlocked).kind(), PollingErrorKind::Other);
+}
+
+#[tokio::test]
+async fn unhandled_updates_are_saved_and_returned_after_restart() {
+    use crate::dispatching::{
+        dialogue::{InMemStorage, Storage},
+        update_listeners::{test_utils::update, StorageOffsetStore},
+    };
+
+    let ids = |updates: &[Update]| updates.iter().map(|upd| upd.id).collect::<Vec<_>>();
+    let key = "teloxide_polling_offset:bot".to_owned();
+
+    let storage = InMemStorage::<SavedOffset, String>::new();
+    let offsets = Arc::new(Offsets::new(StorageOffsetStore::new(Arc::clone(&storage), "bot")));
+    let acknowledger = Arc::clone(&offsets).acknowledger();
+
+    assert_eq!(ids(&offsets.receive(vec![update(1), update(2)])), [1, 2]);
+    assert_eq!(offsets.next(), 3);
+
+    // Updates which have already been received are ignored.
+    assert_eq!(ids(&offsets.receive(vec![update(2), update(3)])), [3]);
+
+    acknowledger.ack(2).await;
+    offsets.save().await;
+
+    let saved = Arc::clone(&storage).get_dialogue(key.clone()).await.unwrap().unwrap();
+    assert_eq!(saved.offset, 4);
+    assert_eq!(ids(&saved.unhandled), [1, 3]);
+
+    // After a restart, unhandled updates are returned again.
+    let restarted = Arc::new(Offsets::new(StorageOffsetStore::new(Arc::clone(&storage), "bot")));
+    let acknowledger = Arc::clone(&restarted).acknowledger();
+    assert_eq!(ids(&restarted.load().await), [1, 3]);
+    assert_eq!(restarted.next(), 4);
+
+    // Acknowledgements are saved right away once the listener is stopped.
+    restarted.finish().await;
+    acknowledger.ack(1).await;
+    acknowledger.ack(3).await;
+
+    let saved = Arc::clone(&storage).get_dialogue(key.clone()).await.unwrap().unwrap();
+    assert_eq!(saved.offset, 4);
+    assert!(saved.unhandled.is_empty());
+}
+
+#[tokio::test]
+async fn unhandled_updates_are_limited() {
+    use crate::dispatching::update_listeners::{test_utils::update, FileOffsetStore};
+
+    let offsets = Arc::new(Offsets::new(FileOffsetStore::new("offset.json")));
+    let acknowledger = Arc::clone(&offsets).acknowledger();
+
+    assert_eq!(offsets.limit(None), None);
+    assert_eq!(offsets.limit(Some(10)), Some(10));
+
+    offsets.receive((1..=90).map(update).collect());
+    assert_eq!(offsets.capacity(), 10);
+    assert_eq!(offsets.limit(None), Some(10));
+    assert_eq!(offsets.limit(Some(5)), Some(5));
+
+    offsets.receive((91..=100).map(update).collect());
+    assert_eq!(offsets.capacity(), 0);
+
+    acknowledger.ack(1).await;
+    assert_eq!(offsets.capacity(), 1);
+    assert_eq!(offsets.limit(None), Some(1));
+}
@@ -0,0 +1,254 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    future,
+    stream::{self, BoxStream, Stream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+};
+
+use crate::{
+    dispatching::{
+        stop_token::{AsyncStopFlag, AsyncStopToken, StopToken},
+        update_listeners::{
+            stateful_listener::StatefulListener, Acknowledger, AsUpdateStream, UpdateListener,
+        },
+    },
+    types::{AllowedUpdate, Update},
+};
+
+/// A line of a file written by [`Record`] and read by [`replay`].
+#[derive(Serialize, Deserialize)]
+struct Entry<U> {
+    /// The time when the update was received, in milliseconds since the Unix
+    /// epoch.
+    timestamp: u64,
+    update: U,
+}
+
+/// A listener returned from [`UpdateListenerExt::record`].
+///
+/// [`UpdateListenerExt::record`]: crate::dispatching::update_listeners::UpdateListenerExt::record
+pub struct Record<L> {
+    listener: L,
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl<L> Record<L> {
+    pub(crate) fn new(listener: L, path: PathBuf) -> Self {
+        Self { listener, path, file: None }
+    }
+}
+
+impl<'a, L, E> AsUpdateStream<'a, E> for Record<L>
+where
+    L: AsUpdateStream<'a, E>,
+{
+    type Stream = BoxStream<'a, Result<Update, E>>;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        let Self { listener, path, file } = self;
+        let updates = Box::pin(listener.as_stream());
+
+        Box::pin(stream::unfold((updates, &*path, file), |(mut updates, path, file)| async move {
+            let res = updates.next().await?;
+
+            if let Ok(upd) = &res {
+                write_update(path, file, upd).await;
+            }
+
+            Some((res, (updates, path, file)))
+        }))
+    }
+}
+
+impl<L, E> UpdateListener<E> for Record<L>
+where
+    Self: for<'a> AsUpdateStream<'a, E>,
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn hint_allowed_updates(&mut self, hint: &mut dyn Iterator<Item = AllowedUpdate>) {
+        self.listener.hint_allowed_updates(hint)
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        self.listener.acknowledger()
+    }
+}
+
+/// Appends `update` to the file at `path`, opening the file if needed.
+///
+/// Recording is auxiliary, so errors are logged instead of being returned.
+async fn write_update(path: &Path, file: &mut Option<File>, update: &Update) {
+    if file.is_none() {
+        match OpenOptions::new().create(true).append(true).open(path).await {
+            Ok(opened) => *file = Some(opened),
+            Err(err) => {
+                log::error!("Failed to open {} to record updates: {}", path.display(), err);
+                return;
+            }
+        }
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default();
+
+    let mut line = match serde_json::to_string(&Entry { timestamp, update }) {
+        Ok(line) => line,
+        Err(err) => {
+            log::error!("Failed to serialize the update {}: {}", update.id, err);
+            return;
+        }
+    };
+    line.push('\n');
+
+    let file = file.as_mut().expect("the file was opened above");
+    if let Err(err) = async {
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+    .await
+    {
+        log::error!("Failed to record the update {}: {}", update.id, err);
+    }
+}
+
+/// Returns an update listener which returns updates from a file written by
+/// [`UpdateListenerExt::record`].
+///
+/// This allows to reproduce a problem by feeding the recorded updates to a
+/// [`Dispatcher`] with a mock bot.
+///
+/// If `keep_timing` is `true`, the listener waits between updates as long as
+/// the time between receiving them originally. Otherwise, the updates are
+/// returned as fast as they are handled.
+///
+/// The listener ends when all the updates are returned. Lines which can't be
+/// parsed are returned as errors, while an I/O error ends the listener.
+///
+/// [`UpdateListenerExt::record`]: crate::dispatching::update_listeners::UpdateListenerExt::record
+/// [`Dispatcher`]: crate::dispatching::Dispatcher
+pub fn replay(
+    path: impl Into<PathBuf>,
+    keep_timing: bool,
+) -> impl UpdateListener<io::Error, StopToken = impl Send + StopToken> {
+    struct State {
+        path: PathBuf,
+        keep_timing: bool,
+        lines: Option<Lines<BufReader<File>>>,
+        last_timestamp: Option<u64>,
+        finished: bool,
+        flag: AsyncStopFlag,
+        token: AsyncStopToken,
+    }
+
+    fn stream(st: &mut State) -> impl Stream<Item = Result<Update, io::Error>> + Send + '_ {
+        stream::unfold(st, |state| async move {
+            if state.finished || state.flag.is_stopped() {
+                return None;
+            }
+
+            if state.lines.is_none() {
+                match File::open(&state.path).await {
+                    Ok(file) => state.lines = Some(BufReader::new(file).lines()),
+                    Err(err) => {
+                        state.finished = true;
+                        return Some((Err(err), state));
+                    }
+                }
+            }
+
+            let lines = state.lines.as_mut().expect("the file was opened above");
+            let line = loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) if line.trim().is_empty() => continue,
+                    Ok(Some(line)) => break line,
+                    Ok(None) => return None,
+                    Err(err) => {
+                        state.finished = true;
+                        return Some((Err(err), state));
+                    }
+                }
+            };
+
+            let Entry { timestamp, update } = match serde_json::from_str::<Entry<Update>>(&line) {
+                Ok(entry) => entry,
+                Err(err) => return Some((Err(err.into()), state)),
+            };
+
+            if state.keep_timing {
+                if let Some(last) = state.last_timestamp {
+                    let delay = Duration::from_millis(timestamp.saturating_sub(last));
+                    let sleep = tokio::time::sleep(delay);
+                    tokio::pin!(sleep);
+
+                    // Wake up early if the listener is stopped.
+                    future::select(sleep, &mut state.flag).await;
+                    if state.flag.is_stopped() {
+                        return None;
+                    }
+                }
+            }
+            state.last_timestamp = Some(timestamp);
+
+            Some((Ok(update), state))
+        })
+    }
+
+    let (token, flag) = AsyncStopToken::new_pair();
+    let state = State {
+        path: path.into(),
+        keep_timing,
+        lines: None,
+        last_timestamp: None,
+        finished: false,
+        flag,
+        token,
+    };
+
+    let stop_token = |st: &mut State| st.token.clone();
+
+    StatefulListener::new(state, stream, stop_token)
+}
+
+#[tokio::test]
+async fn replay_returns_recorded_updates() {
+    use crate::dispatching::update_listeners::{channel, test_utils::update, UpdateListenerExt};
+
+    let path = std::env::temp_dir().join(format!("teloxide-replay-{}.jsonl", std::process::id()));
+
+    let (tx, listener) = channel(2);
+    let mut listener = listener.record(&path);
+    tx.send(update(1)).await.unwrap();
+    tx.send(update(2)).await.unwrap();
+    listener.stop_token().stop();
+
+    let recorded: Vec<_> = listener.as_stream().map(|res| res.unwrap().id).collect().await;
+    let replayed: Vec<_> =
+        replay(&path, true).as_stream().map(|res| res.unwrap().id).collect().await;
+
+    tokio::fs::remove_file(&path).await.unwrap();
+
+    assert_eq!(recorded, [1, 2]);
+    assert_eq!(replayed, [1, 2]);
+}
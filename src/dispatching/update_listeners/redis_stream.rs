@@ -0,0 +1,471 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{
+    future,
+    stream::{self, Stream},
+};
+use redis::{aio::Connection, Client, FromRedisValue, IntoConnectionInfo, RedisError, Value};
+use thiserror::Error;
+
+use crate::{
+    dispatching::{
+        stop_token::{AsyncStopFlag, AsyncStopToken, StopToken},
+        update_listeners::{
+            stateful_listener::StatefulListener, Acknowledger, AsUpdateStream, Backoff,
+            UpdateListener,
+        },
+    },
+    types::{AllowedUpdate, Update},
+};
+
+/// The field of a stream entry which contains an update serialized as JSON.
+const UPDATE_FIELD: &str = "update";
+
+/// An error returned from [`redis_stream`] and [`RedisStreamProducer`].
+#[derive(Debug, Error)]
+pub enum RedisStreamError {
+    #[error("parsing/serializing error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("error from Redis: {0}")]
+    RedisError(#[from] RedisError),
+}
+
+/// Options for [`redis_stream`].
+#[derive(Debug, Clone)]
+pub struct RedisStreamOptions {
+    stream: String,
+    group: String,
+    consumer: String,
+    count: usize,
+    block: Duration,
+    backoff: Backoff,
+}
+
+impl RedisStreamOptions {
+    /// Constructs options for a listener which reads updates from the Redis
+    /// stream with the key `stream` as the `consumer` of the consumer group
+    /// `group`.
+    ///
+    /// All the workers should use the same `group` and different `consumer`
+    /// names. A consumer name should stay the same after a restart, since
+    /// updates which weren't acknowledged before the restart are redelivered
+    /// to the same consumer.
+    pub fn new(
+        stream: impl Into<String>,
+        group: impl Into<String>,
+        consumer: impl Into<String>,
+    ) -> Self {
+        Self {
+            stream: stream.into(),
+            group: group.into(),
+            consumer: consumer.into(),
+            count: 100,
+            block: Duration::from_secs(10),
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// The maximum number of updates read at once, 100 by default.
+    #[must_use]
+    pub fn count(self, count: usize) -> Self {
+        Self { count, ..self }
+    }
+
+    /// How long to wait for new updates in a single request, 10 seconds by
+    /// default.
+    #[must_use]
+    pub fn block(self, block: Duration) -> Self {
+        Self { block, ..self }
+    }
+
+    /// Delays between failed requests.
+    #[must_use]
+    pub fn backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+}
+
+/// Returns an update listener which reads updates from a [Redis stream] using
+/// a consumer group, so that several workers can share the updates.
+///
+/// Updates are added to the stream by [`RedisStreamProducer`], e.g. in a
+/// process which receives them via a [`webhook`]. The consumer group is
+/// created if it doesn't exist.
+///
+/// If the consumer of updates acknowledges them (see
+/// [`UpdateListener::acknowledger`]), a stream entry is acknowledged via
+/// `XACK` once its update has been handled, so updates which weren't handled
+/// because of a crash are redelivered after a restart. Otherwise, an entry is
+/// acknowledged once it is read.
+///
+/// Failed requests are retried according to [`RedisStreamOptions::backoff`],
+/// and the connection is re-established if it was lost.
+///
+/// [Redis stream]: https://redis.io/topics/streams-intro
+/// [`webhook`]: crate::dispatching::update_listeners::webhook
+#[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "redis-storage")))]
+pub fn redis_stream(
+    url: impl IntoConnectionInfo,
+    options: RedisStreamOptions,
+) -> Result<impl UpdateListener<RedisStreamError, StopToken = impl Send + StopToken>, RedisError> {
+    struct State {
+        conn: LazyConnection,
+        acks: Arc<Acks>,
+        options: RedisStreamOptions,
+        started: bool,
+        /// The ID after which entries which were delivered to this consumer
+        /// before, but weren't acknowledged, are read. `None` once all of them
+        /// have been read.
+        pending_cursor: Option<String>,
+        buffered: VecDeque<Result<Update, RedisStreamError>>,
+        /// The number of consecutive failed requests.
+        errors: u32,
+        flag: AsyncStopFlag,
+        token: AsyncStopToken,
+    }
+
+    fn stream(st: &mut State) -> impl Stream<Item = Result<Update, RedisStreamError>> + Send + '_ {
+        stream::unfold(st, |state| async move {
+            loop {
+                if let Some(res) = state.buffered.pop_front() {
+                    return Some((res, state));
+                }
+
+                if state.flag.is_stopped() {
+                    return None;
+                }
+
+                if state.errors > 0 {
+                    let sleep = tokio::time::sleep(state.options.backoff.delay(state.errors));
+                    tokio::pin!(sleep);
+
+                    // Wake up early if the listener is stopped.
+                    future::select(sleep, &mut state.flag).await;
+                    if state.flag.is_stopped() {
+                        return None;
+                    }
+                }
+
+                match read(state).await {
+                    Ok(()) => state.errors = 0,
+                    Err(err) => {
+                        state.errors = state.errors.saturating_add(1);
+                        return Some((Err(err.into()), state));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Reads the next batch of entries into `state.buffered`.
+    async fn read(state: &mut State) -> Result<(), RedisError> {
+        let State { conn, acks, options, started, pending_cursor, buffered, .. } = state;
+
+        if !*started {
+            let res = conn
+                .query::<()>(
+                    redis::cmd("XGROUP")
+                        .arg("CREATE")
+                        .arg(&options.stream)
+                        .arg(&options.group)
+                        .arg("0")
+                        .arg("MKSTREAM"),
+                )
+                .await;
+
+            match res {
+                // The group already exists.
+                Err(err) if err.code() == Some("BUSYGROUP") => {}
+                res => res?,
+            }
+
+            *started = true;
+        }
+
+        let reply = conn
+            .query::<Value>(
+                redis::cmd("XREADGROUP")
+                    .arg("GROUP")
+                    .arg(&options.group)
+                    .arg(&options.consumer)
+                    .arg("COUNT")
+                    .arg(options.count)
+                    .arg("BLOCK")
+                    .arg(options.block.as_millis() as u64)
+                    .arg("STREAMS")
+                    .arg(&options.stream)
+                    .arg(pending_cursor.as_deref().unwrap_or(">")),
+            )
+            .await?;
+        let entries = parse_entries(reply)?;
+
+        if pending_cursor.is_some() {
+            *pending_cursor = entries.last().map(|(id, _)| id.clone());
+        }
+
+        let acks_enabled = acks.enabled.load(Ordering::Relaxed);
+        let mut processed = Vec::new();
+
+        for (id, update) in entries {
+            match serde_json::from_slice::<Update>(&update) {
+                Ok(upd) => {
+                    if acks_enabled {
+                        acks.lock().entry(upd.id).or_default().push(id);
+                    } else {
+                        processed.push(id);
+                    }
+
+                    buffered.push_back(Ok(upd));
+                }
+                Err(err) => {
+                    // The entry will never be parsed, so don't leave it pending.
+                    processed.push(id);
+                    buffered.push_back(Err(err.into()));
+                }
+            }
+        }
+
+        if !processed.is_empty() {
+            conn.query::<()>(&xack(options, &processed)).await?;
+        }
+
+        Ok(())
+    }
+
+    let client = Client::open(url)?;
+
+    let acks = Arc::new(Acks {
+        enabled: AtomicBool::new(false),
+        entries: Mutex::new(HashMap::new()),
+        conn: tokio::sync::Mutex::new(LazyConnection::new(client.clone())),
+        options: options.clone(),
+    });
+
+    let (token, flag) = AsyncStopToken::new_pair();
+    let block = options.block;
+
+    let state = State {
+        conn: LazyConnection::new(client),
+        acks: Arc::clone(&acks),
+        options,
+        started: false,
+        pending_cursor: Some("0".to_owned()),
+        buffered: VecDeque::new(),
+        errors: 0,
+        flag,
+        token,
+    };
+
+    let stop_token = |st: &mut State| st.token.clone();
+    let hint_allowed_updates = None::<fn(&mut State, &mut dyn Iterator<Item = AllowedUpdate>)>;
+    let timeout_hint = Some(move |_: &State| Some(block));
+
+    let listener = StatefulListener::new_with_hints(
+        state,
+        stream,
+        stop_token,
+        hint_allowed_updates,
+        timeout_hint,
+    );
+
+    Ok(RedisStreamListener { listener, acks })
+}
+
+/// A handle which adds updates to a Redis stream, to be read by
+/// [`redis_stream`] listeners.
+pub struct RedisStreamProducer {
+    conn: tokio::sync::Mutex<LazyConnection>,
+    stream: String,
+    max_len: Option<usize>,
+}
+
+impl RedisStreamProducer {
+    /// Creates a producer which adds updates to the stream with the key
+    /// `stream`.
+    ///
+    /// If `max_len` is specified, the stream is trimmed to approximately this
+    /// number of entries, so that handled entries don't pile up.
+    pub fn new(
+        url: impl IntoConnectionInfo,
+        stream: impl Into<String>,
+        max_len: Option<usize>,
+    ) -> Result<Arc<Self>, RedisError> {
+        Ok(Arc::new(Self {
+            conn: tokio::sync::Mutex::new(LazyConnection::new(Client::open(url)?)),
+            stream: stream.into(),
+            max_len,
+        }))
+    }
+
+    /// Adds `update` to the stream.
+    pub async fn send(&self, update: &Update) -> Result<(), RedisStreamError> {
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&self.stream);
+        if let Some(max_len) = self.max_len {
+            cmd.arg("MAXLEN").arg("~").arg(max_len);
+        }
+        cmd.arg("*").arg(UPDATE_FIELD).arg(serde_json::to_vec(update)?);
+
+        self.conn.lock().await.query::<()>(&cmd).await?;
+        Ok(())
+    }
+}
+
+/// Updates which haven't been acknowledged yet.
+struct Acks {
+    enabled: AtomicBool,
+    /// IDs of stream entries by IDs of their updates.
+    entries: Mutex<HashMap<i32, Vec<String>>>,
+    conn: tokio::sync::Mutex<LazyConnection>,
+    options: RedisStreamOptions,
+}
+
+impl Acks {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i32, Vec<String>>> {
+        // The lock is never held across code which can panic.
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn acknowledger(self: Arc<Self>) -> Acknowledger {
+        self.enabled.store(true, Ordering::Relaxed);
+
+        Acknowledger::new(move |update_id| {
+            let this = Arc::clone(&self);
+
+            async move {
+                let ids = match this.lock().remove(&update_id) {
+                    Some(ids) => ids,
+                    None => return,
+                };
+
+                let res = this.conn.lock().await.query::<()>(&xack(&this.options, &ids)).await;
+                if let Err(err) = res {
+                    log::error!("Failed to acknowledge stream entries {:?}: {}", ids, err);
+                }
+            }
+        })
+    }
+}
+
+/// A connection which is established lazily and re-established after it was
+/// lost.
+struct LazyConnection {
+    client: Client,
+    conn: Option<Connection>,
+}
+
+impl LazyConnection {
+    fn new(client: Client) -> Self {
+        Self { client, conn: None }
+    }
+
+    async fn query<T: FromRedisValue>(&mut self, cmd: &redis::Cmd) -> Result<T, RedisError> {
+        if self.conn.is_none() {
+            self.conn = Some(self.client.get_async_connection().await?);
+        }
+
+        let conn = self.conn.as_mut().expect("the connection was established above");
+        let res = cmd.query_async(conn).await;
+
+        if let Err(err) = &res {
+            if err.is_io_error() || err.is_connection_dropped() {
+                self.conn = None;
+            }
+        }
+
+        res
+    }
+}
+
+fn xack(options: &RedisStreamOptions, ids: &[String]) -> redis::Cmd {
+    let mut cmd = redis::cmd("XACK");
+    cmd.arg(&options.stream).arg(&options.group).arg(ids);
+    cmd
+}
+
+/// Parses a reply to `XREADGROUP` into pairs of entry IDs and serialized
+/// updates.
+fn parse_entries(reply: Value) -> Result<Vec<(String, Vec<u8>)>, RedisError> {
+    let invalid_reply =
+        || RedisError::from((redis::ErrorKind::TypeError, "invalid XREADGROUP reply"));
+
+    let streams = match reply {
+        // Timed out.
+        Value::Nil => return Ok(Vec::new()),
+        Value::Bulk(streams) => streams,
+        _ => return Err(invalid_reply()),
+    };
+
+    let mut res = Vec::new();
+    for stream in streams {
+        // Each stream is `[key, [entry, ...]]`.
+        let entries = match stream {
+            Value::Bulk(mut stream) if stream.len() == 2 => stream.pop(),
+            _ => None,
+        };
+        let entries = match entries {
+            Some(Value::Bulk(entries)) => entries,
+            _ => return Err(invalid_reply()),
+        };
+
+        for entry in entries {
+            // Each entry is `[id, [field, value, ...]]`. Fields of pending entries which
+            // were deleted from the stream are nil.
+            let (id, fields) = match entry {
+                Value::Bulk(entry) if entry.len() == 2 => (
+                    String::from_redis_value(&entry[0])?,
+                    Option::<HashMap<String, Vec<u8>>>::from_redis_value(&entry[1])?,
+                ),
+                _ => return Err(invalid_reply()),
+            };
+
+            let update = fields.and_then(|mut fields| fields.remove(UPDATE_FIELD));
+            res.push((id, update.unwrap_or_default()));
+        }
+    }
+
+    Ok(res)
+}
+
+struct RedisStreamListener<L> {
+    listener: L,
+    acks: Arc<Acks>,
+}
+
+impl<'a, L, E> AsUpdateStream<'a, E> for RedisStreamListener<L>
+where
+    L: AsUpdateStream<'a, E>,
+{
+    type Stream = L::Stream;
+
+    fn as_stream(&'a mut self) -> Self::Stream {
+        self.listener.as_stream()
+    }
+}
+
+impl<L, E> UpdateListener<E> for RedisStreamListener<L>
+where
+    L: UpdateListener<E>,
+{
+    type StopToken = L::StopToken;
+
+    fn stop_token(&mut self) -> Self::StopToken {
+        self.listener.stop_token()
+    }
+
+    fn timeout_hint(&self) -> Option<Duration> {
+        self.listener.timeout_hint()
+    }
+
+    fn acknowledger(&mut self) -> Option<Acknowledger> {
+        Some(Arc::clone(&self.acks).acknowledger())
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::types::{Update, UpdateKind};
+
+/// Statistics of an update listener.
+///
+/// This is a handle which is shared between a listener and your code, e.g. to
+/// alert when a bot is stuck. Create it with [`ListenerStats::new`], pass a
+/// clone to a listener (see [`PollingBuilder::stats`] and
+/// [`WebhookOptions::stats`]) and then read the statistics at any time.
+///
+/// [`PollingBuilder::stats`]: crate::dispatching::update_listeners::PollingBuilder::stats
+#[cfg_attr(
+    feature = "webhooks",
+    doc = "[`WebhookOptions::stats`]: crate::dispatching::update_listeners::WebhookOptions::stats"
+)]
+#[cfg_attr(
+    not(feature = "webhooks"),
+    doc = "[`WebhookOptions::stats`]: https://docs.rs/teloxide/latest/teloxide/dispatching/update_listeners/struct.WebhookOptions.html#method.stats"
+)]
+#[derive(Debug, Clone, Default)]
+pub struct ListenerStats(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    last_success: Option<SystemTime>,
+    consecutive_errors: u32,
+    updates_received: u64,
+    newest_update_age: Option<Duration>,
+}
+
+impl ListenerStats {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The time when updates were last received successfully, e.g. when the
+    /// last successful `getUpdates` request finished.
+    ///
+    /// Returns `None` if there were no successful requests yet.
+    #[must_use]
+    pub fn last_success(&self) -> Option<SystemTime> {
+        self.lock().last_success
+    }
+
+    /// The number of consecutive errors since the last success.
+    #[must_use]
+    pub fn consecutive_errors(&self) -> u32 {
+        self.lock().consecutive_errors
+    }
+
+    /// The total number of received updates.
+    #[must_use]
+    pub fn updates_received(&self) -> u64 {
+        self.lock().updates_received
+    }
+
+    /// How old the newest received update was at the time it was received,
+    /// i.e. how much the listener lags behind Telegram.
+    ///
+    /// Only updates which have a date (e.g. messages) are taken into account.
+    /// Returns `None` if no such updates were received yet.
+    #[must_use]
+    pub fn newest_update_age(&self) -> Option<Duration> {
+        self.lock().newest_update_age
+    }
+
+    /// Records that `updates` were received successfully.
+    pub(crate) fn record_success(&self, updates: &[Update]) {
+        let now = SystemTime::now();
+        let age = updates.iter().rev().find_map(update_date).map(|date| {
+            let received = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+            received.saturating_sub(Duration::from_secs(date))
+        });
+
+        let mut inner = self.lock();
+        inner.last_success = Some(now);
+        inner.consecutive_errors = 0;
+        inner.updates_received = inner.updates_received.saturating_add(updates.len() as u64);
+        if age.is_some() {
+            inner.newest_update_age = age;
+        }
+    }
+
+    /// Records that receiving updates failed.
+    pub(crate) fn record_error(&self) {
+        let mut inner = self.lock();
+        inner.consecutive_errors = inner.consecutive_errors.saturating_add(1);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // The lock is never held across code which can panic.
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns the date of `update` as a Unix timestamp, if the update has one.
+fn update_date(update: &Update) -> Option<u64> {
+    let date = match &update.kind {
+        UpdateKind::Message(m)
+        | UpdateKind::EditedMessage(m)
+        | UpdateKind::ChannelPost(m)
+        | UpdateKind::EditedChannelPost(m) => m.date,
+        UpdateKind::MyChatMember(m) | UpdateKind::ChatMember(m) => m.date,
+        UpdateKind::ChatJoinRequest(r) => r.date,
+        _ => return None,
+    };
+
+    u64::try_from(date.timestamp()).ok()
+}
+
+#[test]
+fn stats_record_updates() {
+    let update = |id: i32, date: u64| -> Update {
+        let update = serde_json::json!({ "update_id": id, "message": {
+            "message_id": 1,
+            "date": date,
+            "chat": { "id": 1, "type": "private", "first_name": "A" },
+            "from": { "id": 1, "is_bot": false, "first_name": "A" },
+            "text": "hi",
+        }});
+
+        serde_json::from_value(update).unwrap()
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let stats = ListenerStats::new();
+
+    stats.record_error();
+    stats.record_error();
+    assert_eq!(stats.consecutive_errors(), 2);
+    assert_eq!(stats.last_success(), None);
+
+    stats.record_success(&[update(1, now - 100), update(2, now - 60)]);
+    assert_eq!(stats.consecutive_errors(), 0);
+    assert_eq!(stats.updates_received(), 2);
+    assert!(stats.last_success().is_some());
+
+    let age = stats.newest_update_age().unwrap();
+    assert!(age >= Duration::from_secs(60) && age < Duration::from_secs(100), "{:?}", age);
+}
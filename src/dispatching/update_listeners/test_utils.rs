@@ -0,0 +1,17 @@
+//! Helpers for tests of update listeners.
+//!
+//! This file is also included into `tests/redis.rs`, so it only refers to the
+//! `Update` which is imported by the parent module.
+
+use super::Update;
+
+/// Returns an update with the given ID.
+pub(crate) fn update(id: i32) -> Update {
+    let update = serde_json::json!({ "update_id": id, "poll_answer": {
+        "poll_id": "1",
+        "user": { "id": 1, "is_bot": false, "first_name": "A" },
+        "option_ids": [],
+    }});
+
+    serde_json::from_value(update).unwrap()
+}
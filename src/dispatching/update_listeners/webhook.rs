@@ -0,0 +1,496 @@
+use std::{
+    any::Any,
+    fs, io,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{
+    future::BoxFuture,
+    stream::{self, Stream},
+};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use url::Url;
+use warp::{http::StatusCode, path::FullPath, Filter};
+
+use crate::{
+    dispatching::{
+        stop_token::{AsyncStopToken, StopToken},
+        update_listeners::{stateful_listener::StatefulListener, ListenerStats, UpdateListener},
+    },
+    payloads,
+    requests::{JsonRequest, Payload, Request, Requester},
+    types::{AllowedUpdate, True, Update},
+    Bot, RequestError,
+};
+
+/// The header which Telegram uses to send the secret token of a webhook.
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Options for [`webhook`].
+#[derive(Debug, Clone)]
+pub struct Options {
+    address: SocketAddr,
+    url: Url,
+    secret_token: Option<String>,
+    tls: Option<(PathBuf, PathBuf)>,
+    stats: Option<ListenerStats>,
+}
+
+impl Options {
+    /// Constructs options for a webhook which binds `address` and receives
+    /// updates sent by Telegram to `url`.
+    ///
+    /// `url` is the public URL of the webhook, e.g. `https://example.com/bot`.
+    /// Its path is used to match incoming requests, so it can contain a
+    /// random component to make the webhook harder to guess.
+    pub fn new(address: SocketAddr, url: Url) -> Self {
+        Self { address, url, secret_token: None, tls: None, stats: None }
+    }
+
+    /// Specifies a secret token which must be sent in the
+    /// `X-Telegram-Bot-Api-Secret-Token` header of every request.
+    ///
+    /// The token is passed to Telegram when the webhook is registered, and
+    /// requests with a missing or different token are rejected with `401
+    /// Unauthorized`.
+    #[must_use]
+    pub fn secret_token(self, token: impl Into<String>) -> Self {
+        Self { secret_token: Some(token.into()), ..self }
+    }
+
+    /// Serves the webhook over HTTPS using a certificate and a private key
+    /// from the given files (in the PEM format).
+    ///
+    /// By default, the webhook is served over plain HTTP, which is useful if
+    /// TLS is terminated by a reverse proxy.
+    #[must_use]
+    pub fn tls(self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self { tls: Some((cert_path.into(), key_path.into())), ..self }
+    }
+
+    /// Records statistics of the listener to `stats`.
+    ///
+    /// Every accepted update counts as a success, while a failure to register
+    /// the webhook counts as an error.
+    #[must_use]
+    pub fn stats(self, stats: ListenerStats) -> Self {
+        Self { stats: Some(stats), ..self }
+    }
+}
+
+/// A requester which is built on top of [`Bot`].
+///
+/// [`webhook`] uses [`Bot`] directly to register a webhook, because the
+/// version of the Bot API supported by [`Requester`] doesn't allow to pass a
+/// secret token to [`set_webhook`].
+///
+/// [`set_webhook`]: crate::requests::Requester::set_webhook
+#[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "webhooks")))]
+pub trait AsBot {
+    /// Returns the underlying bot.
+    fn as_bot(&self) -> &Bot;
+}
+
+impl AsBot for Bot {
+    fn as_bot(&self) -> &Bot {
+        self
+    }
+}
+
+#[cfg(feature = "auto-send")]
+impl<B: AsBot> AsBot for crate::adaptors::AutoSend<B> {
+    fn as_bot(&self) -> &Bot {
+        self.inner().as_bot()
+    }
+}
+
+#[cfg(feature = "cache-me")]
+impl<B: AsBot> AsBot for crate::adaptors::CacheMe<B> {
+    fn as_bot(&self) -> &Bot {
+        self.inner().as_bot()
+    }
+}
+
+#[cfg(feature = "throttle")]
+impl<B: AsBot> AsBot for crate::adaptors::Throttle<B> {
+    fn as_bot(&self) -> &Bot {
+        self.inner().as_bot()
+    }
+}
+
+#[cfg(feature = "trace-adaptor")]
+impl<B: AsBot> AsBot for crate::adaptors::Trace<B> {
+    fn as_bot(&self) -> &Bot {
+        self.inner().as_bot()
+    }
+}
+
+impl<B: AsBot> AsBot for crate::adaptors::DefaultParseMode<B> {
+    fn as_bot(&self) -> &Bot {
+        self.inner().as_bot()
+    }
+}
+
+/// The `setWebhook` request with the `secret_token` parameter, which
+/// [`payloads::SetWebhook`] doesn't have yet.
+#[derive(Debug, Clone, Serialize)]
+struct SetWebhookWithSecret {
+    #[serde(flatten)]
+    inner: payloads::SetWebhook,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_token: Option<String>,
+}
+
+impl Payload for SetWebhookWithSecret {
+    type Output = True;
+
+    const NAME: &'static str = "SetWebhook";
+}
+
+/// Returns a webhook update listener.
+///
+/// The listener starts an HTTP(S) server on `options.address` which accepts
+/// updates as `POST` requests to the path of `options.url`.
+///
+/// When the listener starts streaming updates, it registers the webhook via
+/// [`set_webhook`], passing allowed updates from
+/// [`UpdateListener::hint_allowed_updates`] and the secret token from
+/// [`WebhookOptions::secret_token`]. When the listener is stopped, the server
+/// is shut down and the webhook is deleted (if it was registered).
+///
+/// If [`set_webhook`] fails, the error is returned from the stream and the
+/// listener stops.
+///
+/// ## Errors
+///
+/// Returns an error if `options.address` can't be bound, e.g. because it is
+/// already in use, or if the TLS certificate or the private key (see
+/// [`WebhookOptions::tls`]) can't be read or are invalid.
+///
+/// ## Panics
+///
+/// If this function is called outside of the tokio runtime.
+///
+/// [`set_webhook`]: crate::requests::Requester::set_webhook
+/// [`WebhookOptions::secret_token`]: Options::secret_token
+/// [`WebhookOptions::tls`]: Options::tls
+#[cfg_attr(all(docsrs, feature = "nightly"), doc(cfg(feature = "webhooks")))]
+pub fn webhook<R>(
+    bot: R,
+    options: Options,
+) -> io::Result<impl UpdateListener<RequestError, StopToken = impl Send + StopToken>>
+where
+    R: AsBot,
+{
+    struct State {
+        bot: Bot,
+        url: Url,
+        secret_token: Option<String>,
+        /// Whether the webhook is yet to be registered.
+        register: bool,
+        /// Whether the webhook has been registered, so it must be deleted on
+        /// stop.
+        registered: Arc<AtomicBool>,
+        allowed_updates: Option<Vec<AllowedUpdate>>,
+        rx: mpsc::UnboundedReceiver<Update>,
+        token: AsyncStopToken,
+        stats: Option<ListenerStats>,
+    }
+
+    fn stream(st: &mut State) -> impl Stream<Item = Result<Update, RequestError>> + Send + '_ {
+        stream::unfold(st, move |state| async move {
+            if state.register {
+                state.register = false;
+
+                let mut inner = payloads::SetWebhook::new(state.url.clone());
+                inner.allowed_updates = state.allowed_updates.take();
+                let payload =
+                    SetWebhookWithSecret { inner, secret_token: state.secret_token.clone() };
+
+                match JsonRequest::new(state.bot.clone(), payload).send().await {
+                    Ok(_) => state.registered.store(true, Ordering::Relaxed),
+                    Err(err) => {
+                        if let Some(stats) = &state.stats {
+                            stats.record_error();
+                        }
+
+                        // Shutting down the server closes the channel, so the stream ends
+                        // after the error.
+                        state.token.clone().stop();
+                        return Some((Err(err), state));
+                    }
+                }
+            }
+
+            state.rx.recv().await.map(|upd| (Ok(upd), state))
+        })
+    }
+
+    let Options { address, url, secret_token, tls, stats } = options;
+    let bot = bot.as_bot().clone();
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (token, flag) = AsyncStopToken::new_pair();
+
+    let server =
+        warp::serve(filter(url.path().to_owned(), secret_token.clone(), tx, stats.clone()));
+    let server: BoxFuture<'static, ()> = match tls {
+        Some((cert_path, key_path)) => {
+            let cert = read_pem(&cert_path, "CERTIFICATE")?;
+            let key = read_pem(&key_path, "PRIVATE KEY")?;
+
+            // `warp` panics instead of returning errors when it can't configure TLS or
+            // bind the address.
+            let bind = AssertUnwindSafe(|| {
+                server.tls().cert(cert).key(key).bind_with_graceful_shutdown(address, flag)
+            });
+            let (_, server) = panic::catch_unwind(bind).map_err(|payload| {
+                io::Error::new(io::ErrorKind::Other, panic_message(payload.as_ref()))
+            })?;
+
+            Box::pin(server)
+        }
+        None => Box::pin(
+            server
+                .try_bind_with_graceful_shutdown(address, flag)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+                .1,
+        ),
+    };
+
+    let registered = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let registered = Arc::clone(&registered);
+        let delete_webhook = bot.delete_webhook();
+        async move {
+            server.await;
+
+            if registered.load(Ordering::Relaxed) {
+                if let Err(err) = delete_webhook.send().await {
+                    log::error!("Failed to delete a webhook: {:?}", err);
+                }
+            }
+        }
+    });
+
+    let state = State {
+        bot,
+        url,
+        secret_token,
+        register: true,
+        registered,
+        allowed_updates: None,
+        rx,
+        token,
+        stats,
+    };
+
+    let stop_token = |st: &mut State| st.token.clone();
+
+    let hint_allowed_updates =
+        Some(|state: &mut State, allowed: &mut dyn Iterator<Item = AllowedUpdate>| {
+            state.allowed_updates = Some(allowed.collect());
+        });
+    let timeout_hint = Some(|_: &State| None::<Duration>);
+
+    Ok(StatefulListener::new_with_hints(
+        state,
+        stream,
+        stop_token,
+        hint_allowed_updates,
+        timeout_hint,
+    ))
+}
+
+/// Reads a PEM file for the TLS configuration and checks that it contains
+/// `label` (e.g. `CERTIFICATE`), because `warp` panics on invalid files.
+fn read_pem(path: &Path, label: &str) -> io::Result<Vec<u8>> {
+    let pem = fs::read(path)?;
+    let text = String::from_utf8_lossy(&pem);
+
+    if !text.lines().any(|line| line.starts_with("-----BEGIN ") && line.contains(label)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't contain a PEM-encoded {}", path.display(), label),
+        ));
+    }
+
+    Ok(pem)
+}
+
+/// Extracts a message from a panic payload.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<String>(), payload.downcast_ref::<&str>()) {
+        (Some(message), _) => message.clone(),
+        (_, Some(message)) => (*message).to_owned(),
+        _ => "failed to start a webhook server".to_owned(),
+    }
+}
+
+/// A rejection of a request with a missing or invalid secret token.
+#[derive(Debug)]
+struct InvalidSecretToken;
+
+impl warp::reject::Reject for InvalidSecretToken {}
+
+/// Returns a `warp` filter which accepts updates at `path` and sends them to
+/// `tx`.
+///
+/// The path and the secret token are checked before the body is read.
+fn filter(
+    path: String,
+    secret_token: Option<String>,
+    tx: mpsc::UnboundedSender<Update>,
+    stats: Option<ListenerStats>,
+) -> impl Filter<Extract = (StatusCode,), Error = warp::Rejection> + Clone {
+    let path_matches = warp::path::full()
+        .and_then(move |full_path: FullPath| {
+            let found = full_path.as_str() == path;
+            async move {
+                if found {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one();
+
+    let authorized = warp::header::optional::<String>(SECRET_TOKEN_HEADER)
+        .and_then(move |token: Option<String>| {
+            let valid = secret_token.is_none() || token == secret_token;
+            async move {
+                if valid {
+                    Ok(())
+                } else {
+                    log::warn!("Rejected a webhook request with an invalid secret token");
+                    Err(warp::reject::custom(InvalidSecretToken))
+                }
+            }
+        })
+        .untuple_one();
+
+    warp::post()
+        .and(path_matches)
+        .and(authorized)
+        .and(warp::body::json())
+        .map(move |update: Update| {
+            if let Some(stats) = &stats {
+                stats.record_success(std::slice::from_ref(&update));
+            }
+
+            match tx.send(update) {
+                Ok(()) => StatusCode::OK,
+                // The listener was dropped, so Telegram should retry later.
+                Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+            }
+        })
+        .recover(|rejection: warp::Rejection| async move {
+            match rejection.find::<InvalidSecretToken>() {
+                Some(InvalidSecretToken) => Ok(StatusCode::UNAUTHORIZED),
+                None => Err(rejection),
+            }
+        })
+        .unify()
+}
+
+#[test]
+fn webhook_is_send() {
+    use crate::dispatching::update_listeners::AsUpdateStream;
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let _guard = rt.enter();
+
+    let bot = crate::Bot::new("TOKEN");
+    let options =
+        Options::new(([127, 0, 0, 1], 0).into(), "https://example.com/bot".parse().unwrap());
+    let mut webhook = webhook(bot, options).unwrap();
+
+    assert_send(&webhook);
+    assert_send(&webhook.as_stream());
+    assert_send(&webhook.stop_token());
+
+    fn assert_send(_: &impl Send) {}
+}
+
+#[tokio::test]
+async fn webhook_returns_bind_errors() {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let bot = crate::Bot::new("TOKEN");
+    let options = Options::new(address, "https://example.com/bot".parse().unwrap());
+    assert!(webhook(bot, options).is_err());
+}
+
+#[tokio::test]
+async fn webhook_returns_tls_errors() {
+    let dir = std::env::temp_dir().join(format!("teloxide-webhook-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, "not a certificate").unwrap();
+    fs::write(&key_path, "not a key").unwrap();
+
+    let bot = crate::Bot::new("TOKEN");
+    let options =
+        Options::new(([127, 0, 0, 1], 0).into(), "https://example.com/bot".parse().unwrap());
+
+    let missing = options.clone().tls(dir.join("missing.pem"), &key_path);
+    let err = webhook(bot.clone(), missing).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    let invalid = options.tls(&cert_path, &key_path);
+    let err = webhook(bot, invalid).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn webhook_filter() {
+    const UPDATE: &str = r#"{
+        "update_id": 1,
+        "message": {
+            "message_id": 1,
+            "date": 1,
+            "chat": { "id": 1, "type": "private", "first_name": "A" },
+            "from": { "id": 1, "is_bot": false, "first_name": "A" },
+            "text": "hi"
+        }
+    }"#;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let stats = ListenerStats::new();
+    let filter = filter("/bot".to_owned(), Some("secret".to_owned()), tx, Some(stats.clone()));
+
+    let request = || {
+        warp::test::request()
+            .method("POST")
+            .path("/bot")
+            .header("content-type", "application/json")
+            .header(SECRET_TOKEN_HEADER, "secret")
+            .body(UPDATE)
+    };
+
+    let res = request().reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(rx.try_recv().unwrap().id, 1);
+
+    let res = request().header(SECRET_TOKEN_HEADER, "wrong").reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = request().path("/other").reply(&filter).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    assert!(rx.try_recv().is_err());
+    assert_eq!(stats.updates_received(), 1);
+}
@@ -0,0 +1,49 @@
+use crate::dispatching2::dialogue::{ChatUserKey, GetChatId, GetUserId, UserKey};
+
+/// A key of a dialogue which can be extracted from an update of type `Upd`.
+///
+/// The key determines who shares a dialogue:
+///
+///  - `i64` -- a chat ID, i.e. all members of a group chat share one dialogue.
+///  - [`ChatUserKey`] -- a chat ID and a user ID, i.e. each member of a group
+///    chat has a separate dialogue.
+///  - [`UserKey`] -- a user ID, i.e. a user has one dialogue in all chats.
+///
+/// You can implement this trait for your own type to use it as a key, e.g. to
+/// have a dialogue per inline message. Pass the key type to
+/// [`HandlerExt::enter_dialogue_with_key`].
+///
+/// [`HandlerExt::enter_dialogue_with_key`]: crate::dispatching2::HandlerExt::enter_dialogue_with_key
+pub trait DialogueKey<Upd>: Sized {
+    /// Extracts a key from `upd`, or returns `None` if `upd` can't be a part
+    /// of a dialogue.
+    #[must_use]
+    fn from_update(upd: &Upd) -> Option<Self>;
+}
+
+impl<Upd> DialogueKey<Upd> for i64
+where
+    Upd: GetChatId,
+{
+    fn from_update(upd: &Upd) -> Option<Self> {
+        upd.chat_id()
+    }
+}
+
+impl<Upd> DialogueKey<Upd> for ChatUserKey
+where
+    Upd: GetChatId + GetUserId,
+{
+    fn from_update(upd: &Upd) -> Option<Self> {
+        Some(ChatUserKey::new(upd.chat_id()?, upd.user_id()?))
+    }
+}
+
+impl<Upd> DialogueKey<Upd> for UserKey
+where
+    Upd: GetUserId,
+{
+    fn from_update(upd: &Upd) -> Option<Self> {
+        upd.user_id().map(UserKey)
+    }
+}
@@ -1,7 +1,15 @@
-use crate::types::CallbackQuery;
+use crate::types::{
+    CallbackQuery, ChatJoinRequest, ChatMemberUpdated, ChosenInlineResult, InlineQuery, PollAnswer,
+    PreCheckoutQuery, ShippingQuery, Update, UpdateKind,
+};
 use teloxide_core::types::Message;
 
 /// Something that may has a chat ID.
+///
+/// Updates which are not sent from a chat (e.g. [`InlineQuery`]) return the ID
+/// of a user who has sent them, which is the same as the ID of a private chat
+/// with this user. This way, dialogues work uniformly with all kinds of
+/// updates.
 pub trait GetChatId {
     #[must_use]
     fn chat_id(&self) -> Option<i64>;
@@ -13,8 +21,92 @@ impl GetChatId for Message {
     }
 }
 
+/// Returns the ID of a chat with the message, or the ID of a user who has
+/// pressed the button if the message was sent via inline mode.
 impl GetChatId for CallbackQuery {
     fn chat_id(&self) -> Option<i64> {
-        self.message.as_ref().map(|mes| mes.chat.id)
+        Some(self.message.as_ref().map_or(self.from.id, |mes| mes.chat.id))
     }
 }
+
+impl GetChatId for ChatMemberUpdated {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.chat.id)
+    }
+}
+
+impl GetChatId for ChatJoinRequest {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.chat.id)
+    }
+}
+
+impl GetChatId for InlineQuery {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetChatId for ChosenInlineResult {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetChatId for ShippingQuery {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetChatId for PreCheckoutQuery {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetChatId for PollAnswer {
+    fn chat_id(&self) -> Option<i64> {
+        Some(self.user.id)
+    }
+}
+
+/// Returns `None` for [`UpdateKind::Poll`] and [`UpdateKind::Error`], which are
+/// related to neither a chat nor a user.
+impl GetChatId for Update {
+    fn chat_id(&self) -> Option<i64> {
+        match &self.kind {
+            UpdateKind::Message(m)
+            | UpdateKind::EditedMessage(m)
+            | UpdateKind::ChannelPost(m)
+            | UpdateKind::EditedChannelPost(m) => m.chat_id(),
+            UpdateKind::CallbackQuery(q) => q.chat_id(),
+            UpdateKind::MyChatMember(m) | UpdateKind::ChatMember(m) => m.chat_id(),
+            UpdateKind::ChatJoinRequest(r) => r.chat_id(),
+            UpdateKind::InlineQuery(q) => q.chat_id(),
+            UpdateKind::ChosenInlineResult(r) => r.chat_id(),
+            UpdateKind::ShippingQuery(q) => q.chat_id(),
+            UpdateKind::PreCheckoutQuery(q) => q.chat_id(),
+            UpdateKind::PollAnswer(a) => a.chat_id(),
+            UpdateKind::Poll(_) | UpdateKind::Error(_) => None,
+        }
+    }
+}
+
+#[test]
+fn update_without_chat_returns_user_id() {
+    use crate::dispatching2::dialogue::GetUserId;
+
+    let update: Update = serde_json::from_value(serde_json::json!({
+        "update_id": 1,
+        "poll_answer": {
+            "poll_id": "1",
+            "user": { "id": 42, "is_bot": false, "first_name": "A" },
+            "option_ids": [],
+        },
+    }))
+    .unwrap();
+
+    assert_eq!(update.chat_id(), Some(42));
+    assert_eq!(update.user_id(), Some(42));
+}
@@ -0,0 +1,106 @@
+use crate::types::{
+    CallbackQuery, ChatJoinRequest, ChatMemberUpdated, ChosenInlineResult, InlineQuery, PollAnswer,
+    PreCheckoutQuery, ShippingQuery, Update, UpdateKind,
+};
+use teloxide_core::types::Message;
+
+/// Something that may has a user ID.
+pub trait GetUserId {
+    #[must_use]
+    fn user_id(&self) -> Option<i64>;
+}
+
+/// Returns `None` for messages sent to channels.
+impl GetUserId for Message {
+    fn user_id(&self) -> Option<i64> {
+        self.from().map(|user| user.id)
+    }
+}
+
+impl GetUserId for CallbackQuery {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+/// Returns the ID of a member whose status has changed, rather than of a user
+/// who has changed it. For [`UpdateKind::MyChatMember`], this is the bot
+/// itself.
+impl GetUserId for ChatMemberUpdated {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.new_chat_member.user.id)
+    }
+}
+
+impl GetUserId for ChatJoinRequest {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for InlineQuery {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for ChosenInlineResult {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for ShippingQuery {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for PreCheckoutQuery {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.from.id)
+    }
+}
+
+impl GetUserId for PollAnswer {
+    fn user_id(&self) -> Option<i64> {
+        Some(self.user.id)
+    }
+}
+
+/// Returns `None` for [`UpdateKind::Poll`] and [`UpdateKind::Error`], which are
+/// not sent by a user.
+impl GetUserId for Update {
+    fn user_id(&self) -> Option<i64> {
+        match &self.kind {
+            UpdateKind::Message(m)
+            | UpdateKind::EditedMessage(m)
+            | UpdateKind::ChannelPost(m)
+            | UpdateKind::EditedChannelPost(m) => m.user_id(),
+            UpdateKind::CallbackQuery(q) => q.user_id(),
+            UpdateKind::MyChatMember(m) | UpdateKind::ChatMember(m) => m.user_id(),
+            UpdateKind::ChatJoinRequest(r) => r.user_id(),
+            UpdateKind::InlineQuery(q) => q.user_id(),
+            UpdateKind::ChosenInlineResult(r) => r.user_id(),
+            UpdateKind::ShippingQuery(q) => q.user_id(),
+            UpdateKind::PreCheckoutQuery(q) => q.user_id(),
+            UpdateKind::PollAnswer(a) => a.user_id(),
+            UpdateKind::Poll(_) | UpdateKind::Error(_) => None,
+        }
+    }
+}
+
+#[test]
+fn chat_member_updated_returns_the_member() {
+    let user = |id: i64| serde_json::json!({ "id": id, "is_bot": false, "first_name": "A" });
+    let update: ChatMemberUpdated = serde_json::from_value(serde_json::json!({
+        "chat": { "id": -100, "type": "group", "title": "G" },
+        "from": user(1),
+        "date": 0,
+        "old_chat_member": { "user": user(2), "status": "left" },
+        "new_chat_member": { "user": user(2), "status": "member" },
+    }))
+    .unwrap();
+
+    assert_eq!(update.user_id(), Some(2));
+}
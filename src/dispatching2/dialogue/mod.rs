@@ -93,45 +93,63 @@ pub use crate::dispatching::dialogue::{RedisStorage, RedisStorageError};
 pub use crate::dispatching::dialogue::{SqliteStorage, SqliteStorageError};
 
 pub use crate::dispatching::dialogue::{
-    serializer, InMemStorage, InMemStorageError, Serializer, Storage, TraceStorage,
+    serializer, ChatUserKey, EnumerableStorage, InMemStorage, InMemStorageError, ParseKeyError,
+    Serializer, Storage, TraceStorage, TtlStorage, UserKey,
 };
+pub use dialogue_key::DialogueKey;
 pub use get_chat_id::GetChatId;
+pub use get_user_id::GetUserId;
 
-use std::{marker::PhantomData, sync::Arc};
+use std::{marker::PhantomData, sync::Arc, time::Duration};
 
+mod dialogue_key;
 mod get_chat_id;
+mod get_user_id;
 
 /// A handle for controlling dialogue state.
+///
+/// A dialogue is indexed by a key of type `K`, which is a chat ID by default.
+/// See [`DialogueKey`] for other kinds of keys.
 #[derive(Debug)]
-pub struct Dialogue<D, S> {
+pub struct Dialogue<D, S, K = i64> {
     storage: Arc<S>,
-    chat_id: i64,
+    key: K,
     _phantom: PhantomData<D>,
 }
 
 // `#[derive]` requires generics to implement `Clone`, but `S` is wrapped around
 // `Arc`, and `D` is wrapped around PhantomData.
-impl<D, S> Clone for Dialogue<D, S> {
+impl<D, S, K> Clone for Dialogue<D, S, K>
+where
+    K: Clone,
+{
     fn clone(&self) -> Self {
-        Dialogue { storage: self.storage.clone(), chat_id: self.chat_id, _phantom: PhantomData }
+        Dialogue { storage: self.storage.clone(), key: self.key.clone(), _phantom: PhantomData }
     }
 }
 
-impl<D, S> Dialogue<D, S>
+impl<D, S, K> Dialogue<D, S, K>
 where
     D: Send + 'static,
-    S: Storage<D>,
+    S: Storage<D, K>,
+    K: Clone,
 {
     /// Constructs a new dialogue with `storage` (where dialogues are stored)
-    /// and `chat_id` of a current dialogue.
-    pub fn new(storage: Arc<S>, chat_id: i64) -> Self {
-        Self { storage, chat_id, _phantom: PhantomData }
+    /// and `key` of a current dialogue, e.g. a chat ID.
+    pub fn new(storage: Arc<S>, key: K) -> Self {
+        Self { storage, key, _phantom: PhantomData }
+    }
+
+    /// Returns the key of the dialogue.
+    #[must_use]
+    pub fn key(&self) -> &K {
+        &self.key
     }
 
     /// Retrieves the current state of the dialogue or `None` if there is no
     /// dialogue.
     pub async fn get(&self) -> Result<Option<D>, S::Error> {
-        self.storage.clone().get_dialogue(self.chat_id).await
+        self.storage.clone().get_dialogue(self.key.clone()).await
     }
 
     /// Like [`Dialogue::get`] but returns a default value if there is no
@@ -143,7 +161,7 @@ where
         match self.get().await? {
             Some(d) => Ok(d),
             None => {
-                self.storage.clone().update_dialogue(self.chat_id, D::default()).await?;
+                self.storage.clone().update_dialogue(self.key.clone(), D::default()).await?;
                 Ok(D::default())
             }
         }
@@ -158,10 +176,39 @@ where
         D: From<State>,
     {
         let new_dialogue = state.into();
-        self.storage.clone().update_dialogue(self.chat_id, new_dialogue).await?;
+        self.storage.clone().update_dialogue(self.key.clone(), new_dialogue).await?;
         Ok(())
     }
 
+    /// Like [`Dialogue::update`] but the dialogue expires after `ttl`.
+    ///
+    /// An expired dialogue is treated as if there is no dialogue. Wrap your
+    /// storage into [`TtlStorage`] to be notified about expired dialogues.
+    pub async fn update_with_ttl<State>(&self, state: State, ttl: Duration) -> Result<(), S::Error>
+    where
+        D: From<State>,
+    {
+        let new_dialogue = state.into();
+        self.storage.clone().update_dialogue_with_ttl(self.key.clone(), new_dialogue, ttl).await?;
+        Ok(())
+    }
+
+    /// Atomically updates the dialogue state with the result of `f`, which is
+    /// called with the current state (`None` if there is no dialogue).
+    ///
+    /// Use it instead of [`Dialogue::get`] followed by [`Dialogue::update`]
+    /// when the same dialogue can be updated concurrently. `f` may be called
+    /// more than once, see [`Storage::update_dialogue_with`].
+    pub async fn update_with<F>(&self, f: F) -> Result<(), S::Error>
+    where
+        S: Send + Sync + 'static,
+        S::Error: Send + 'static,
+        K: Send + 'static,
+        F: FnMut(Option<D>) -> D + Send + 'static,
+    {
+        self.storage.clone().update_dialogue_with(self.key.clone(), None, f).await
+    }
+
     /// Updates the dialogue with a default value.
     pub async fn reset(&self) -> Result<(), S::Error>
     where
@@ -172,6 +219,6 @@ where
 
     /// Removes the dialogue from the storage provided to [`Dialogue::new`].
     pub async fn exit(&self) -> Result<(), S::Error> {
-        self.storage.clone().remove_dialogue(self.chat_id).await
+        self.storage.clone().remove_dialogue(self.key.clone()).await
     }
 }
@@ -1,18 +1,49 @@
 use crate::{
-    adaptors::CacheMe,
     dispatching::{
-        stop_token::StopToken, update_listeners, update_listeners::UpdateListener, ShutdownToken,
+        stop_token::StopToken,
+        update_listeners,
+        update_listeners::{Acknowledger, PollingError, UpdateListener},
+        ShutdownToken,
+    },
+    dispatching2::{
+        dialogue::GetChatId,
+        error_handler::IgnoringDeps,
+        middleware::{Middlewares, Next},
+        Middleware, UpdateError, UpdateErrorHandler,
     },
     error_handlers::{ErrorHandler, LoggingErrorHandler},
     requests::Requester,
-    types::{AllowedUpdate, Update},
+    types::{AllowedUpdate, Me, Update},
     utils::shutdown_token::shutdown_check_timeout_for,
 };
 use dptree::di::{DependencyMap, DependencySupplier};
-use futures::{future::BoxFuture, StreamExt};
-use std::{collections::HashSet, fmt::Debug, ops::ControlFlow, sync::Arc};
-use teloxide_core::requests::{Request, RequesterExt};
-use tokio::time::timeout;
+use futures::{
+    future::{self, join_all, BoxFuture, Either},
+    FutureExt, StreamExt,
+};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    mem,
+    ops::ControlFlow,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use teloxide_core::requests::Request;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify, OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
 
 use std::future::Future;
 
@@ -22,7 +53,15 @@ pub struct DispatcherBuilder<R, Err> {
     dependencies: DependencyMap,
     handler: UpdateHandler<Err>,
     default_handler: DefaultHandler,
-    error_handler: Arc<dyn ErrorHandler<Err>>,
+    error_handler: Arc<dyn UpdateErrorHandler<UpdateError<Err>> + Send + Sync>,
+    middlewares: Vec<Arc<dyn Middleware<Err> + Send + Sync>>,
+    allowed_updates: HashSet<AllowedUpdate>,
+    worker_queue_size: usize,
+    max_pending_updates: usize,
+    handler_timeout: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    get_me_retries: u32,
+    get_me_backoff: Duration,
 }
 
 impl<R, Err> DispatcherBuilder<R, Err>
@@ -36,13 +75,13 @@ where
     #[must_use]
     pub fn default_handler<H, Fut>(self, handler: H) -> Self
     where
-        H: Fn(Arc<Update>) -> Fut + 'static,
+        H: Fn(Arc<Update>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = ()> + Send + 'static,
     {
         let handler = Arc::new(handler);
 
         Self {
-            default_handler: Box::new(move |upd| {
+            default_handler: Arc::new(move |upd| {
                 let handler = Arc::clone(&handler);
                 Box::pin(handler(upd))
             }),
@@ -53,11 +92,40 @@ where
     /// Specifies a handler that will be called on a handler error.
     ///
     /// By default, it is [`LoggingErrorHandler`].
+    ///
+    /// Panics of handlers can't be passed to [`ErrorHandler`], so they are
+    /// logged via [`log::error`] instead. To handle them, use
+    /// [`DispatcherBuilder::update_error_handler`].
+    #[must_use]
+    pub fn error_handler(self, handler: Arc<dyn ErrorHandler<Err> + Send + Sync>) -> Self {
+        Self { error_handler: Arc::new(IgnoringDeps(handler)), ..self }
+    }
+
+    /// Specifies a handler that will be called on a handler error or panic,
+    /// along with dependencies of the update that caused it.
+    ///
+    /// This replaces a handler set by [`DispatcherBuilder::error_handler`].
     #[must_use]
-    pub fn error_handler(self, handler: Arc<dyn ErrorHandler<Err>>) -> Self {
+    pub fn update_error_handler(
+        self,
+        handler: Arc<dyn UpdateErrorHandler<UpdateError<Err>> + Send + Sync>,
+    ) -> Self {
         Self { error_handler: handler, ..self }
     }
 
+    /// Adds a middleware which will be run for every update.
+    ///
+    /// Middlewares are run in the order they were added, i.e., the first added
+    /// middleware is the outermost one. See [`Middleware`] for more.
+    #[must_use]
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware<Err> + Send + Sync + 'static,
+    {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
     /// Specifies dependencies that can be used inside of handlers.
     ///
     /// By default, there is no dependencies.
@@ -66,44 +134,223 @@ where
         Self { dependencies, ..self }
     }
 
+    /// Specifies which kinds of updates the bot wants to receive.
+    ///
+    /// The set is passed to the update listener via
+    /// [`UpdateListener::hint_allowed_updates`], e.g. [`polling()`] sends it as
+    /// [`GetUpdates::allowed_updates`]. Note that Telegram doesn't send
+    /// [`AllowedUpdate::ChatMember`] updates unless they are requested
+    /// explicitly, so if your handler uses
+    /// [`UpdateFilterExt::filter_chat_member`], you need to list it here
+    /// (together with all other kinds of updates you handle).
+    ///
+    /// The set can't be inferred from the handler, because `dptree` handlers
+    /// are opaque.
+    ///
+    /// By default, no hint is given, so the listener uses its own settings
+    /// (for [`polling()`] this means all kinds of updates except
+    /// [`AllowedUpdate::ChatMember`]).
+    ///
+    /// [`polling()`]: crate::dispatching::update_listeners::polling
+    /// [`GetUpdates::allowed_updates`]: crate::payloads::GetUpdates::allowed_updates
+    /// [`UpdateFilterExt::filter_chat_member`]: crate::dispatching2::UpdateFilterExt::filter_chat_member
+    #[must_use]
+    pub fn allowed_updates<I>(self, allowed_updates: I) -> Self
+    where
+        I: IntoIterator<Item = AllowedUpdate>,
+    {
+        Self { allowed_updates: allowed_updates.into_iter().collect(), ..self }
+    }
+
+    /// Specifies how many updates from a single chat can wait in the queue of
+    /// the chat's worker.
+    ///
+    /// When a queue of some chat is full, further updates from this chat are
+    /// parked (in order) until there is space in the queue, while updates from
+    /// other chats are still dispatched. Parked updates count towards
+    /// [`DispatcherBuilder::max_pending_updates`], which is what eventually
+    /// stops receiving new updates.
+    ///
+    /// By default, it is 64.
+    ///
+    /// ## Panics
+    ///
+    /// If `size` is 0.
+    #[must_use]
+    pub fn worker_queue_size(self, size: usize) -> Self {
+        assert!(size > 0, "worker queue size must be greater than 0");
+        Self { worker_queue_size: size, ..self }
+    }
+
+    /// Specifies how many updates can be received from the update listener,
+    /// but not yet handled, across all chats.
+    ///
+    /// This limits both the number of concurrently running handlers and the
+    /// number of updates waiting in the queues of chats. When the limit is
+    /// reached, the dispatcher stops receiving new updates from the update
+    /// listener until some of the handlers finish.
+    ///
+    /// By default, it is 1024.
+    ///
+    /// ## Panics
+    ///
+    /// If `max` is 0.
+    #[must_use]
+    pub fn max_pending_updates(self, max: usize) -> Self {
+        assert!(max > 0, "max pending updates must be greater than 0");
+        Self { max_pending_updates: max, ..self }
+    }
+
+    /// Specifies how long handling of a single update can take.
+    ///
+    /// If handling of an update (including middlewares) doesn't finish in
+    /// `timeout`, it is aborted and [`UpdateError::Timeout`] is passed to the
+    /// error handler, so that the next updates from the same chat can be
+    /// handled.
+    ///
+    /// To set a timeout for a specific branch, use [`HandlerExt::timeout`].
+    ///
+    /// By default, there is no timeout.
+    ///
+    /// [`HandlerExt::timeout`]: crate::dispatching2::HandlerExt::timeout
+    #[must_use]
+    pub fn handler_timeout(self, timeout: Duration) -> Self {
+        Self { handler_timeout: Some(timeout), ..self }
+    }
+
+    /// Specifies how long to wait for running handlers when dispatching is
+    /// stopped.
+    ///
+    /// When dispatching stops (e.g., because of [`ShutdownToken::shutdown`]),
+    /// no new updates are received, but the updates which were already
+    /// received are still handled. If handling doesn't finish in `timeout`,
+    /// the remaining handlers are aborted, and their updates, along with the
+    /// updates which haven't been handled yet, are passed to the error handler
+    /// as [`UpdateError::Aborted`].
+    ///
+    /// By default, the dispatcher waits for all handlers to finish.
+    #[must_use]
+    pub fn shutdown_timeout(self, timeout: Duration) -> Self {
+        Self { shutdown_timeout: Some(timeout), ..self }
+    }
+
+    /// Specifies how many times to retry retrieving [`Me`] when dispatching
+    /// starts.
+    ///
+    /// [`Me`] is retrieved once, before receiving any updates. If the request
+    /// fails, it is retried up to `retries` times; the delay before the first
+    /// retry is `backoff` and it is doubled after each failed retry (up to 1
+    /// minute). If all the retries fail, dispatching doesn't start and the
+    /// error is returned from [`Dispatcher::try_dispatch`].
+    ///
+    /// By default, 5 retries are made, starting with a 1 second delay.
+    #[must_use]
+    pub fn get_me_retries(self, retries: u32, backoff: Duration) -> Self {
+        Self { get_me_retries: retries, get_me_backoff: backoff, ..self }
+    }
+
     /// Constructs [`Dispatcher`].
     #[must_use]
     pub fn build(self) -> Dispatcher<R, Err> {
         Dispatcher {
-            bot: self.bot.clone(),
-            cache_me_bot: self.bot.cache_me(),
+            bot: self.bot,
             dependencies: self.dependencies,
-            handler: self.handler,
+            handler: Arc::new(self.handler),
             default_handler: self.default_handler,
             error_handler: self.error_handler,
-            allowed_updates: Default::default(),
+            middlewares: Arc::new(self.middlewares),
+            allowed_updates: self.allowed_updates,
+            workers: HashMap::new(),
+            default_worker: None,
+            worker_queue_size: self.worker_queue_size,
+            pending_updates_limit: Arc::new(Semaphore::new(self.max_pending_updates)),
+            parked_updates: 0,
+            queue_freed: Arc::new(Notify::new()),
+            abort_handling: CancellationToken::new(),
+            handler_timeout: self.handler_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            get_me_retries: self.get_me_retries,
+            get_me_backoff: self.get_me_backoff,
+            acknowledger: None,
             state: ShutdownToken::new(),
         }
     }
 }
 
 /// The base for update dispatching.
+///
+/// Updates are handled concurrently, but updates from the same chat are always
+/// handled sequentially, in the order they were received. Each chat gets its
+/// own worker (a spawned task with a queue of updates); updates that have no
+/// chat are distributed by their sender (e.g., inline queries) and updates
+/// that have neither (e.g., polls) are handled by a single default worker.
+///
+/// The number of updates waiting to be handled is bounded (see
+/// [`DispatcherBuilder::max_pending_updates`]), so when handlers can't keep up,
+/// the dispatcher stops receiving updates from the update listener instead of
+/// buffering them in memory. A single slow chat doesn't stop the dispatcher:
+/// its updates are parked until its worker catches up (see
+/// [`DispatcherBuilder::worker_queue_size`]).
 pub struct Dispatcher<R, Err> {
     bot: R,
-    cache_me_bot: CacheMe<R>,
     dependencies: DependencyMap,
 
-    handler: UpdateHandler<Err>,
+    handler: Arc<UpdateHandler<Err>>,
     default_handler: DefaultHandler,
-    error_handler: Arc<dyn ErrorHandler<Err>>,
-    // TODO: respect allowed_udpates
+    error_handler: Arc<dyn UpdateErrorHandler<UpdateError<Err>> + Send + Sync>,
+    middlewares: Middlewares<Err>,
     allowed_updates: HashSet<AllowedUpdate>,
 
+    workers: HashMap<i64, Worker>,
+    default_worker: Option<Worker>,
+    worker_queue_size: usize,
+    pending_updates_limit: Arc<Semaphore>,
+    /// The number of updates in [`Worker::parked`] of all workers.
+    parked_updates: usize,
+    /// Notified by workers when they take an update from their queues.
+    queue_freed: Arc<Notify>,
+    /// Cancelled when [`DispatcherBuilder::shutdown_timeout`] expires.
+    abort_handling: CancellationToken,
+    handler_timeout: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    get_me_retries: u32,
+    get_me_backoff: Duration,
+    acknowledger: Option<Acknowledger>,
+
     state: ShutdownToken,
 }
 
+/// A task which sequentially handles updates from a single chat.
+struct Worker {
+    tx: mpsc::Sender<WorkerItem>,
+    /// Updates which didn't fit into the queue of the worker, in the order
+    /// they were received.
+    parked: VecDeque<WorkerItem>,
+    handle: JoinHandle<()>,
+    /// The number of updates which were sent to this worker, but are not yet
+    /// handled.
+    pending: Arc<AtomicUsize>,
+}
+
+type WorkerItem = (Update, OwnedSemaphorePermit);
+
+const DEFAULT_WORKER_QUEUE_SIZE: usize = 64;
+const DEFAULT_MAX_PENDING_UPDATES: usize = 1024;
+
+const DEFAULT_GET_ME_RETRIES: u32 = 5;
+const DEFAULT_GET_ME_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_GET_ME_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Idle workers are removed once the number of workers exceeds this value.
+const WORKERS_CLEANUP_THRESHOLD: usize = 512;
+
 // TODO: it is allowed to return message as response on telegram request in
 // webhooks, so we can allow this too. See more there: https://core.telegram.org/bots/api#making-requests-when-getting-updates
 
 /// A handler that processes updates from Telegram.
 pub type UpdateHandler<Err> = dptree::Handler<'static, DependencyMap, Result<(), Err>>;
 
-type DefaultHandler = Box<dyn Fn(Arc<Update>) -> BoxFuture<'static, ()>>;
+type DefaultHandler = Arc<dyn Fn(Arc<Update>) -> BoxFuture<'static, ()> + Send + Sync>;
 
 impl<R, Err> Dispatcher<R, Err>
 where
@@ -120,11 +367,19 @@ where
             bot,
             dependencies: DependencyMap::new(),
             handler,
-            default_handler: Box::new(|upd| {
+            default_handler: Arc::new(|upd| {
                 log::warn!("Unhandled update: {:?}", upd);
                 Box::pin(async {})
             }),
-            error_handler: LoggingErrorHandler::new(),
+            error_handler: Arc::new(IgnoringDeps(LoggingErrorHandler::new())),
+            middlewares: Vec::new(),
+            allowed_updates: HashSet::new(),
+            worker_queue_size: DEFAULT_WORKER_QUEUE_SIZE,
+            max_pending_updates: DEFAULT_MAX_PENDING_UPDATES,
+            handler_timeout: None,
+            shutdown_timeout: None,
+            get_me_retries: DEFAULT_GET_ME_RETRIES,
+            get_me_backoff: DEFAULT_GET_ME_BACKOFF,
         }
     }
 
@@ -141,6 +396,11 @@ where
     ///  - An update from Telegram;
     ///  - [`crate::types::Me`] (can be used in [`HandlerExt::filter_command`]).
     ///
+    /// [`Me`] is retrieved once, when dispatching starts (see
+    /// [`DispatcherBuilder::get_me_retries`]). If it can't be retrieved, the
+    /// error is logged and dispatching doesn't start. Use
+    /// [`Dispatcher::try_dispatch`] to handle the error yourself.
+    ///
     /// [`shutdown`]: ShutdownToken::shutdown
     /// [a ctrlc signal]: Dispatcher::setup_ctrlc_handler
     /// [`HandlerExt::filter_command`]: crate::dispatching2::HandlerExt::filter_command
@@ -148,12 +408,11 @@ where
     where
         R: Requester + Clone,
         <R as Requester>::GetUpdates: Send,
+        <R as Requester>::Err: PollingError,
     {
-        let listener = update_listeners::polling_default(self.bot.clone()).await;
-        let error_handler =
-            LoggingErrorHandler::with_custom_text("An error from the update listener");
-
-        self.dispatch_with_listener(listener, error_handler).await;
+        if let Err(err) = self.try_dispatch().await {
+            log::error!("Failed to start dispatching: {:?}", err);
+        }
     }
 
     /// Starts your bot with custom `update_listener` and
@@ -161,18 +420,64 @@ where
     ///
     /// This method adds the same dependencies as [`Dispatcher::dispatch`].
     ///
+    /// If the listener supports acknowledgements (see
+    /// [`UpdateListener::acknowledger`]), every update is acknowledged once it
+    /// has been handled, even if a handler failed. Updates whose handling was
+    /// aborted because of [`DispatcherBuilder::shutdown_timeout`] are not
+    /// acknowledged, so that they can be received again after a restart.
+    ///
     /// [`shutdown`]: ShutdownToken::shutdown
     /// [a ctrlc signal]: Dispatcher::setup_ctrlc_handler
     pub async fn dispatch_with_listener<'a, UListener, ListenerE, Eh>(
         &'a mut self,
-        mut update_listener: UListener,
+        update_listener: UListener,
         update_listener_error_handler: Arc<Eh>,
     ) where
         UListener: UpdateListener<ListenerE> + 'a,
         Eh: ErrorHandler<ListenerE> + 'a,
         ListenerE: Debug,
     {
-        update_listener.hint_allowed_updates(&mut self.allowed_updates.clone().into_iter());
+        if let Err(err) =
+            self.try_dispatch_with_listener(update_listener, update_listener_error_handler).await
+        {
+            log::error!("Failed to start dispatching: {:?}", err);
+        }
+    }
+
+    /// Same as [`Dispatcher::dispatch`], but returns an error if [`Me`] can't
+    /// be retrieved.
+    pub async fn try_dispatch(&mut self) -> Result<(), R::Err>
+    where
+        R: Requester + Clone,
+        <R as Requester>::GetUpdates: Send,
+        <R as Requester>::Err: PollingError,
+    {
+        let listener = update_listeners::polling_default(self.bot.clone()).await;
+        let error_handler =
+            LoggingErrorHandler::with_custom_text("An error from the update listener");
+
+        self.try_dispatch_with_listener(listener, error_handler).await
+    }
+
+    /// Same as [`Dispatcher::dispatch_with_listener`], but returns an error if
+    /// [`Me`] can't be retrieved.
+    pub async fn try_dispatch_with_listener<'a, UListener, ListenerE, Eh>(
+        &'a mut self,
+        mut update_listener: UListener,
+        update_listener_error_handler: Arc<Eh>,
+    ) -> Result<(), R::Err>
+    where
+        UListener: UpdateListener<ListenerE> + 'a,
+        Eh: ErrorHandler<ListenerE> + 'a,
+        ListenerE: Debug,
+    {
+        let me = self.get_me().await?;
+        self.dependencies.insert(me);
+
+        if !self.allowed_updates.is_empty() {
+            update_listener.hint_allowed_updates(&mut self.allowed_updates.iter().cloned());
+        }
+        self.acknowledger = update_listener.acknowledger();
 
         let shutdown_check_timeout = shutdown_check_timeout_for(&update_listener);
         let mut stop_token = Some(update_listener.stop_token());
@@ -183,14 +488,32 @@ where
             let stream = update_listener.as_stream();
             tokio::pin!(stream);
 
+            let queue_freed = Arc::clone(&self.queue_freed);
+
             loop {
-                // False positive
-                #[allow(clippy::collapsible_match)]
-                if let Ok(upd) = timeout(shutdown_check_timeout, stream.next()).await {
-                    match upd {
-                        None => break,
-                        Some(upd) => self.process_update(upd, &update_listener_error_handler).await,
+                let next = timeout(shutdown_check_timeout, stream.next());
+                let upd = if self.parked_updates == 0 {
+                    next.await.ok()
+                } else {
+                    // Parked updates must be moved to their queues as soon as there is
+                    // space, even if no new updates arrive.
+                    let freed = queue_freed.notified();
+                    tokio::pin!(next, freed);
+
+                    match future::select(next, freed).await {
+                        Either::Left((upd, _)) => upd.ok(),
+                        Either::Right(((), _)) => None,
                     }
+                };
+
+                self.unpark_updates();
+
+                match upd {
+                    Some(None) => break,
+                    Some(Some(upd)) => {
+                        self.process_update(upd, &update_listener_error_handler).await
+                    }
+                    None => {}
                 }
 
                 if self.state.is_shutting_down() {
@@ -203,13 +526,35 @@ where
             }
         }
 
-        // TODO: wait for executing handlers?
+        self.wait_for_workers().await;
 
         self.state.done();
+
+        Ok(())
+    }
+
+    /// Retrieves [`Me`], retrying with an exponential backoff on errors.
+    async fn get_me(&self) -> Result<Me, R::Err> {
+        let mut retries_left = self.get_me_retries;
+        let mut backoff = self.get_me_backoff;
+
+        loop {
+            match self.bot.get_me().send().await {
+                Ok(me) => return Ok(me),
+                Err(err) if retries_left > 0 => {
+                    log::warn!("Failed to retrieve 'me', retrying in {:?}: {}", backoff, err);
+
+                    tokio::time::sleep(backoff).await;
+                    retries_left -= 1;
+                    backoff = backoff.saturating_mul(2).min(MAX_GET_ME_BACKOFF);
+                }
+                Err(err) => return Err(err),
+            }
+        }
     }
 
     async fn process_update<LErr, LErrHandler>(
-        &self,
+        &mut self,
         update: Result<Update, LErr>,
         err_handler: &Arc<LErrHandler>,
     ) where
@@ -217,25 +562,215 @@ where
     {
         match update {
             Ok(upd) => {
-                let mut deps = self.dependencies.clone();
+                let permit = Arc::clone(&self.pending_updates_limit)
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed");
+
+                let worker = match distribution_key(&upd) {
+                    Some(key) => {
+                        if !self.workers.contains_key(&key) {
+                            self.remove_idle_workers();
+                            let worker = self.spawn_worker();
+                            self.workers.insert(key, worker);
+                        }
+
+                        self.workers.get_mut(&key).expect("the worker was inserted above")
+                    }
+                    None => {
+                        if self.default_worker.is_none() {
+                            self.default_worker = Some(self.spawn_worker());
+                        }
+
+                        self.default_worker.as_mut().expect("the worker was inserted above")
+                    }
+                };
+
+                worker.pending.fetch_add(1, Ordering::Relaxed);
+                worker.parked.push_back((upd, permit));
+                self.parked_updates += 1;
+                self.parked_updates -= worker.unpark();
+            }
+            Err(err) => err_handler.clone().handle_error(err).await,
+        }
+    }
+
+    /// Moves parked updates to the queues of their workers, as long as there
+    /// is space.
+    fn unpark_updates(&mut self) {
+        if self.parked_updates == 0 {
+            return;
+        }
+
+        let unparked: usize = self
+            .workers
+            .values_mut()
+            .chain(self.default_worker.as_mut())
+            .filter(|worker| !worker.parked.is_empty())
+            .map(Worker::unpark)
+            .sum();
+        self.parked_updates -= unparked;
+    }
+
+    fn spawn_worker(&self) -> Worker {
+        let (tx, mut rx) = mpsc::channel(self.worker_queue_size);
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let bot = self.bot.clone();
+        let dependencies = self.dependencies.clone();
+        let handler = Arc::clone(&self.handler);
+        let default_handler = Arc::clone(&self.default_handler);
+        let error_handler = Arc::clone(&self.error_handler);
+        let middlewares = Arc::clone(&self.middlewares);
+        let handler_timeout = self.handler_timeout;
+        let acknowledger = self.acknowledger.clone();
+        let queue_freed = Arc::clone(&self.queue_freed);
+        let abort_handling = self.abort_handling.clone();
+        let pending_local = Arc::clone(&pending);
+
+        let handle = tokio::spawn(async move {
+            while let Some((upd, permit)) = rx.recv().await {
+                queue_freed.notify_one();
+                let update_id = upd.id;
+
+                let mut deps = dependencies.clone();
                 deps.insert(upd);
-                deps.insert(self.bot.clone());
-                deps.insert(
-                    self.cache_me_bot.get_me().send().await.expect("Failed to retrieve 'me'"),
-                );
+                deps.insert(bot.clone());
+
+                let handling = async {
+                    let next = Next::new(Arc::clone(&middlewares), Arc::clone(&handler));
+                    let run = AssertUnwindSafe(next.run(deps.clone())).catch_unwind();
+                    let res = match handler_timeout {
+                        Some(duration) => timeout(duration, run).await.map_err(|_| duration),
+                        None => Ok(run.await),
+                    };
 
-                match self.handler.dispatch(deps).await {
-                    ControlFlow::Break(Ok(())) => {}
-                    ControlFlow::Break(Err(err)) => {
-                        self.error_handler.clone().handle_error(err).await
+                    match res {
+                        Ok(Ok(ControlFlow::Break(Ok(())))) => {}
+                        Ok(Ok(ControlFlow::Break(Err(err)))) => {
+                            error_handler
+                                .clone()
+                                .handle_error(UpdateError::Handler(err), deps.clone())
+                                .await
+                        }
+                        Ok(Ok(ControlFlow::Continue(deps))) => {
+                            let upd = deps.get();
+                            (default_handler)(upd).await;
+                        }
+                        Ok(Err(payload)) => {
+                            let message = panic_message(payload);
+                            error_handler
+                                .clone()
+                                .handle_error(UpdateError::Panic(message), deps.clone())
+                                .await
+                        }
+                        Err(duration) => {
+                            error_handler
+                                .clone()
+                                .handle_error(UpdateError::Timeout(duration), deps.clone())
+                                .await
+                        }
                     }
-                    ControlFlow::Continue(deps) => {
-                        let upd = deps.get();
-                        (self.default_handler)(upd).await;
+                };
+
+                // Once handling has been aborted, the rest of the queue is only reported
+                // to the error handler. Panics in the default handler and the error handler
+                // can't be reported anywhere, but they still must not kill the worker.
+                let cancelled = abort_handling.cancelled();
+                let handling = AssertUnwindSafe(handling).catch_unwind();
+                tokio::pin!(cancelled, handling);
+
+                let (aborted, res) = match future::select(cancelled, handling).await {
+                    Either::Left(((), _)) => {
+                        let reporting =
+                            error_handler.clone().handle_error(UpdateError::Aborted, deps.clone());
+                        (true, AssertUnwindSafe(reporting).catch_unwind().await)
                     }
+                    Either::Right((res, _)) => (false, res),
+                };
+
+                if let Err(payload) = res {
+                    log::error!(
+                        "A panic occurred while handling the update #{}: {}",
+                        update_id,
+                        panic_message(payload)
+                    );
                 }
+
+                // Aborted updates are not acknowledged, so that they are received again.
+                if !aborted {
+                    if let Some(acknowledger) = &acknowledger {
+                        acknowledger.ack(update_id).await;
+                    }
+                }
+
+                pending_local.fetch_sub(1, Ordering::Relaxed);
+                drop(permit);
+            }
+        });
+
+        Worker { tx, parked: VecDeque::new(), handle, pending }
+    }
+
+    /// Removes workers which have no pending updates, if there are too many
+    /// workers.
+    ///
+    /// A worker is only removed if it has nothing to handle, so that a new
+    /// worker for the same chat could not run concurrently with the old one.
+    fn remove_idle_workers(&mut self) {
+        if self.workers.len() < WORKERS_CLEANUP_THRESHOLD {
+            return;
+        }
+
+        // Dropping `Worker::tx` makes the worker's task finish.
+        self.workers.retain(|_, worker| worker.pending.load(Ordering::Relaxed) != 0);
+    }
+
+    /// Waits until all the workers have handled their pending updates.
+    ///
+    /// If the shutdown timeout expires, handling is aborted and the remaining
+    /// updates are reported to the error handler instead.
+    async fn wait_for_workers(&mut self) {
+        log::debug!("Waiting for handlers to finish");
+
+        // Dropping the senders makes workers finish once their queues are empty, so
+        // parked updates are sent from separate tasks which drop the senders
+        // afterwards.
+        self.parked_updates = 0;
+        let handles =
+            self.workers.drain().map(|(_, worker)| worker).chain(self.default_worker.take()).map(
+                |Worker { tx, parked, handle, .. }| {
+                    if !parked.is_empty() {
+                        tokio::spawn(async move {
+                            for item in parked {
+                                if tx.send(item).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+
+                    handle
+                },
+            );
+
+        // The token is replaced, so that dispatching can be started again.
+        let abort_handling = mem::replace(&mut self.abort_handling, CancellationToken::new());
+        let all_finished = join_all(handles);
+        tokio::pin!(all_finished);
+
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            if timeout(shutdown_timeout, all_finished.as_mut()).await.is_err() {
+                log::warn!(
+                    "Handlers didn't finish in {:?} after dispatching was stopped, aborting them",
+                    shutdown_timeout
+                );
+                abort_handling.cancel();
             }
-            Err(err) => err_handler.clone().handle_error(err).await,
+        }
+
+        for err in all_finished.await.into_iter().filter_map(Result::err) {
+            log::error!("A dispatcher's worker failed: {}", err);
         }
     }
 
@@ -272,3 +807,63 @@ where
         self.state.clone()
     }
 }
+
+impl Worker {
+    /// Moves parked updates to the queue of the worker, as long as there is
+    /// space, and returns the number of updates which are no longer parked.
+    fn unpark(&mut self) -> usize {
+        let mut unparked = 0;
+
+        while let Some(item) = self.parked.pop_front() {
+            match self.tx.try_send(item) {
+                Ok(()) => unparked += 1,
+                Err(TrySendError::Full(item)) => {
+                    self.parked.push_front(item);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    log::error!("A dispatcher's worker has been terminated unexpectedly");
+                    unparked += 1 + self.parked.len();
+                    self.parked.clear();
+                    break;
+                }
+            }
+        }
+
+        unparked
+    }
+}
+
+/// Extracts a message from a panic payload.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "Box<dyn Any>".to_owned(),
+        },
+    }
+}
+
+/// Returns a key by which updates are distributed among workers: an ID of a
+/// chat or, if there is no chat, an ID of a user.
+fn distribution_key(update: &Update) -> Option<i64> {
+    update.chat_id()
+}
+
+#[test]
+fn dispatcher_is_send() {
+    use std::convert::Infallible;
+
+    let bot = crate::Bot::new("TOKEN");
+    let handler: UpdateHandler<Infallible> = dptree::entry();
+
+    let builder = Dispatcher::builder(bot, handler);
+    assert_send(&builder);
+
+    let mut dispatcher = builder.build();
+    assert_send(&dispatcher);
+    assert_send(&dispatcher.dispatch());
+
+    fn assert_send(_: &impl Send) {}
+}
@@ -0,0 +1,119 @@
+use crate::error_handlers::ErrorHandler;
+use dptree::di::DependencyMap;
+use futures::future::BoxFuture;
+use std::{future::Future, sync::Arc, time::Duration};
+
+/// An error which occurred while handling an update.
+///
+/// This is what [`DispatcherBuilder::update_error_handler`] receives.
+///
+/// [`DispatcherBuilder::update_error_handler`]: crate::dispatching2::DispatcherBuilder::update_error_handler
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UpdateError<E> {
+    /// A handler returned an error.
+    Handler(E),
+
+    /// A handler panicked with this message.
+    Panic(String),
+
+    /// Handling of the update took longer than this duration.
+    ///
+    /// See [`DispatcherBuilder::handler_timeout`].
+    ///
+    /// [`DispatcherBuilder::handler_timeout`]: crate::dispatching2::DispatcherBuilder::handler_timeout
+    Timeout(Duration),
+
+    /// Handling of the update was aborted, or it was not handled at all,
+    /// because dispatching has been stopped and handlers didn't finish in
+    /// time.
+    ///
+    /// See [`DispatcherBuilder::shutdown_timeout`].
+    ///
+    /// [`DispatcherBuilder::shutdown_timeout`]: crate::dispatching2::DispatcherBuilder::shutdown_timeout
+    Aborted,
+}
+
+/// An asynchronous handler of an error which occurred while handling an
+/// update.
+///
+/// Unlike [`ErrorHandler`], it also receives dependencies of the update that
+/// caused the error: the [`Update`] itself, your bot, [`Me`], and everything
+/// passed to [`DispatcherBuilder::dependencies`]. This allows, for example,
+/// to tell a user that something went wrong.
+///
+/// ## Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use teloxide::{
+///     dispatching2::{UpdateError, UpdateHandler},
+///     dptree::di::{DependencyMap, DependencySupplier},
+///     prelude2::*,
+///     RequestError,
+/// };
+///
+/// # fn f(handler: UpdateHandler<RequestError>) {
+/// let bot = Bot::from_env().auto_send();
+///
+/// Dispatcher::builder(bot, handler)
+///     .update_error_handler(Arc::new(
+///         |err: UpdateError<RequestError>, deps: DependencyMap| async move {
+///             let update: Arc<Update> = deps.get();
+///             log::error!("Failed to handle the update #{}: {:?}", update.id, err);
+///         },
+///     ))
+///     .build();
+/// # }
+/// ```
+///
+/// [`Update`]: crate::types::Update
+/// [`Me`]: crate::types::Me
+/// [`DispatcherBuilder::dependencies`]: crate::dispatching2::DispatcherBuilder::dependencies
+pub trait UpdateErrorHandler<E> {
+    #[must_use]
+    fn handle_error(self: Arc<Self>, error: E, deps: DependencyMap) -> BoxFuture<'static, ()>;
+}
+
+impl<E, F, Fut> UpdateErrorHandler<E> for F
+where
+    F: Fn(E, DependencyMap) -> Fut + Send + Sync + 'static,
+    E: Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    fn handle_error(self: Arc<Self>, error: E, deps: DependencyMap) -> BoxFuture<'static, ()> {
+        Box::pin(async move { self(error, deps).await })
+    }
+}
+
+/// An [`UpdateErrorHandler`] which passes errors to an [`ErrorHandler`],
+/// ignoring dependencies.
+///
+/// Panics and timeouts are logged via [`log::error`], aborted updates via
+/// [`log::warn`].
+pub(crate) struct IgnoringDeps<E>(pub(crate) Arc<dyn ErrorHandler<E> + Send + Sync>);
+
+impl<E> UpdateErrorHandler<UpdateError<E>> for IgnoringDeps<E> {
+    fn handle_error(
+        self: Arc<Self>,
+        error: UpdateError<E>,
+        _: DependencyMap,
+    ) -> BoxFuture<'static, ()> {
+        match error {
+            UpdateError::Handler(error) => Arc::clone(&self.0).handle_error(error),
+            UpdateError::Panic(message) => {
+                log::error!("A handler panicked: {}", message);
+                Box::pin(async {})
+            }
+            UpdateError::Timeout(duration) => {
+                log::error!("A handler has timed out after {:?}", duration);
+                Box::pin(async {})
+            }
+            UpdateError::Aborted => {
+                log::warn!("Handling of an update was aborted on shutdown");
+                Box::pin(async {})
+            }
+        }
+    }
+}
@@ -1,8 +1,8 @@
-use std::sync::Arc;
+use std::{ops::ControlFlow, sync::Arc, time::Duration};
 
 use crate::{
     dispatching2::{
-        dialogue::{Dialogue, GetChatId, Storage},
+        dialogue::{Dialogue, DialogueKey, GetChatId, Storage},
         HandlerFactory,
     },
     types::{Me, Message},
@@ -50,10 +50,48 @@ pub trait HandlerExt<Output> {
         D: Default + Send + Sync + 'static,
         Upd: GetChatId + Clone + Send + Sync + 'static;
 
+    /// Like [`HandlerExt::enter_dialogue`] but dialogues are indexed by keys
+    /// of type `K` extracted from updates, and [`Dialogue<D, S, K>`] is passed
+    /// as a handler dependency.
+    ///
+    /// For example, `enter_dialogue_with_key::<Message, S, D, ChatUserKey>()`
+    /// gives each member of a group chat a separate dialogue. See
+    /// [`DialogueKey`] for the available keys.
+    ///
+    /// ## Dependency requirements
+    ///
+    ///  - `Arc<S>`
+    ///  - `Upd`
+    ///
+    /// [`Dialogue<D, S, K>`]: Dialogue
+    #[must_use]
+    fn enter_dialogue_with_key<Upd, S, D, K>(self) -> Self
+    where
+        S: Storage<D, K> + Send + Sync + 'static,
+        <S as Storage<D, K>>::Error: Debug + Send,
+        D: Default + Send + Sync + 'static,
+        K: DialogueKey<Upd> + Clone + Send + Sync + 'static,
+        Upd: Clone + Send + Sync + 'static;
+
     #[must_use]
     fn dispatch_by<F>(self) -> Self
     where
         F: HandlerFactory<Out = Output>;
+
+    /// Returns a handler that aborts `self` if it doesn't finish in
+    /// `duration`.
+    ///
+    /// When `self` is aborted, the result of `on_timeout` is returned instead,
+    /// e.g., an error which will then be passed to the error handler. Handlers
+    /// chained after the returned handler are not limited by `duration`.
+    ///
+    /// See also: [`DispatcherBuilder::handler_timeout`].
+    ///
+    /// [`DispatcherBuilder::handler_timeout`]: crate::dispatching2::DispatcherBuilder::handler_timeout
+    #[must_use]
+    fn timeout<F>(self, duration: Duration, on_timeout: F) -> Self
+    where
+        F: Fn() -> Output + Send + Sync + 'static;
 }
 
 impl<Output> HandlerExt<Output> for Handler<'static, DependencyMap, Output>
@@ -76,12 +114,23 @@ where
         <S as Storage<D>>::Error: Debug + Send,
         D: Default + Send + Sync + 'static,
         Upd: GetChatId + Clone + Send + Sync + 'static,
+    {
+        self.enter_dialogue_with_key::<Upd, S, D, i64>()
+    }
+
+    fn enter_dialogue_with_key<Upd, S, D, K>(self) -> Self
+    where
+        S: Storage<D, K> + Send + Sync + 'static,
+        <S as Storage<D, K>>::Error: Debug + Send,
+        D: Default + Send + Sync + 'static,
+        K: DialogueKey<Upd> + Clone + Send + Sync + 'static,
+        Upd: Clone + Send + Sync + 'static,
     {
         self.chain(dptree::filter_map(|storage: Arc<S>, upd: Upd| {
-            let chat_id = upd.chat_id()?;
-            Some(Dialogue::new(storage, chat_id))
+            let key = K::from_update(&upd)?;
+            Some(Dialogue::new(storage, key))
         }))
-        .chain(dptree::filter_map_async(|dialogue: Dialogue<D, S>| async move {
+        .chain(dptree::filter_map_async(|dialogue: Dialogue<D, S, K>| async move {
             match dialogue.get_or_default().await {
                 Ok(dialogue) => Some(dialogue),
                 Err(err) => {
@@ -98,4 +147,24 @@ where
     {
         self.chain(F::handler())
     }
+
+    fn timeout<F>(self, duration: Duration, on_timeout: F) -> Self
+    where
+        F: Fn() -> Output + Send + Sync + 'static,
+    {
+        let on_timeout = Arc::new(on_timeout);
+
+        dptree::from_fn(move |deps: DependencyMap, cont| {
+            let this = self.clone();
+            let on_timeout = Arc::clone(&on_timeout);
+
+            async move {
+                match tokio::time::timeout(duration, this.dispatch(deps)).await {
+                    Ok(ControlFlow::Continue(deps)) => cont(deps).await,
+                    Ok(done) => done,
+                    Err(_) => ControlFlow::Break(on_timeout()),
+                }
+            }
+        })
+    }
 }
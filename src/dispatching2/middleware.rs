@@ -0,0 +1,103 @@
+use crate::dispatching2::UpdateHandler;
+use dptree::di::DependencyMap;
+use futures::future::BoxFuture;
+use std::{future::Future, ops::ControlFlow, sync::Arc};
+
+/// Something that wraps handling of every update.
+///
+/// Middlewares are registered via [`DispatcherBuilder::middleware`] and are run
+/// for every update, before the [`UpdateHandler`]. A middleware receives
+/// dependencies of the update (which it can inspect or augment) and [`Next`],
+/// which represents the rest of the middlewares and the handler. A middleware
+/// can:
+///
+///  - Call [`Next::run`] to pass the update forwards and observe the result
+/// of its handling.
+///  - Return without calling [`Next::run`] to stop processing the update.
+///
+/// The result has the same meaning as the result of
+/// [`dptree::Handler::dispatch`]: `ControlFlow::Break(_)` if the update was
+/// handled, `ControlFlow::Continue(deps)` if it wasn't (in which case, the
+/// default handler is called).
+///
+/// ## Examples
+///
+/// ```no_run
+/// use std::time::Instant;
+///
+/// use teloxide::{
+///     dispatching2::{Next, UpdateHandler},
+///     dptree::di::DependencyMap,
+///     prelude2::*,
+/// };
+///
+/// # fn f(handler: UpdateHandler<teloxide::RequestError>) {
+/// let bot = Bot::from_env().auto_send();
+///
+/// Dispatcher::builder(bot, handler)
+///     .middleware(|deps: DependencyMap, next: Next<teloxide::RequestError>| async move {
+///         let start = Instant::now();
+///         let result = next.run(deps).await;
+///         log::info!("An update was handled in {:?}", start.elapsed());
+///         result
+///     })
+///     .build();
+/// # }
+/// ```
+///
+/// [`DispatcherBuilder::middleware`]: crate::dispatching2::DispatcherBuilder::middleware
+pub trait Middleware<Err> {
+    #[must_use]
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, ControlFlow<Result<(), Err>, DependencyMap>>;
+}
+
+impl<Err, F, Fut> Middleware<Err> for F
+where
+    F: Fn(DependencyMap, Next<Err>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ControlFlow<Result<(), Err>, DependencyMap>> + Send + 'static,
+{
+    fn handle(
+        self: Arc<Self>,
+        deps: DependencyMap,
+        next: Next<Err>,
+    ) -> BoxFuture<'static, ControlFlow<Result<(), Err>, DependencyMap>> {
+        Box::pin(self(deps, next))
+    }
+}
+
+pub(crate) type Middlewares<Err> = Arc<Vec<Arc<dyn Middleware<Err> + Send + Sync>>>;
+
+/// The rest of the middlewares and the handler.
+///
+/// See [`Middleware`].
+pub struct Next<Err> {
+    middlewares: Middlewares<Err>,
+    index: usize,
+    handler: Arc<UpdateHandler<Err>>,
+}
+
+impl<Err> Next<Err>
+where
+    Err: Send + Sync + 'static,
+{
+    pub(crate) fn new(middlewares: Middlewares<Err>, handler: Arc<UpdateHandler<Err>>) -> Self {
+        Self { middlewares, index: 0, handler }
+    }
+
+    /// Passes the update (represented by `deps`) to the next middleware or,
+    /// if there are no more middlewares, to the handler.
+    #[must_use]
+    pub fn run(
+        self,
+        deps: DependencyMap,
+    ) -> BoxFuture<'static, ControlFlow<Result<(), Err>, DependencyMap>> {
+        match self.middlewares.get(self.index).cloned() {
+            Some(middleware) => middleware.handle(deps, Self { index: self.index + 1, ..self }),
+            None => Box::pin(async move { self.handler.dispatch(deps).await }),
+        }
+    }
+}
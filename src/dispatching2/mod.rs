@@ -77,6 +77,11 @@
 //! no handler succeeds at handling the update, [`Dispatcher`] will invoke a
 //! default handler set up via [`DispatcherBuilder::default_handler`].
 //!
+//! Things that should happen for every update regardless of the branch that
+//! handles it (authorization, logging, timing, etc.) can be implemented as a
+//! [`Middleware`] instead of being repeated in every branch (see
+//! [`DispatcherBuilder::middleware`]).
+//!
 //! Update pipelining provides several advantages over the typical `match
 //! (update.kind) { ... }` approach:
 //!
@@ -99,11 +104,15 @@ pub mod repls;
 
 pub mod dialogue;
 mod dispatcher;
+mod error_handler;
 mod filter_ext;
 mod handler_ext;
 mod handler_factory;
+mod middleware;
 
 pub use dispatcher::{Dispatcher, DispatcherBuilder, UpdateHandler};
+pub use error_handler::{UpdateError, UpdateErrorHandler};
 pub use filter_ext::{MessageFilterExt, UpdateFilterExt};
 pub use handler_ext::HandlerExt;
 pub use handler_factory::HandlerFactory;
+pub use middleware::{Middleware, Next};
@@ -31,6 +31,7 @@ where
     H: Injectable<DependencyMap, Result<(), E>, Args> + Send + Sync + 'static,
     R: Requester + Clone + Send + Sync + 'static,
     <R as Requester>::GetUpdates: Send,
+    <R as Requester>::Err: update_listeners::PollingError,
     E: Debug + Send + Sync + 'static,
 {
     let cloned_bot = bot.clone();
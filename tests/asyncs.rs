@@ -36,7 +36,9 @@ mod tests {
             dptree::entry().filter_command::<SimpleCommand>().endpoint(simple_commands_handler),
         );
         let mut disp = Dispatcher::builder(bot, handler).dependencies(dptree::deps![some_data]).build();
-        let f_disp = disp.setup_ctrlc_handler().dispatch();
-        let worker = tokio::spawn(f_disp);
+        let worker = tokio::spawn(async move {
+            disp.setup_ctrlc_handler().dispatch().await;
+        });
+        worker.abort();
     }
 }
@@ -0,0 +1,372 @@
+use std::{
+    convert::Infallible,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use teloxide::{
+    dispatching::update_listeners::{channel, UpdateSender},
+    dispatching2::{Dispatcher, HandlerExt, Next, UpdateError},
+    dptree::{
+        self,
+        di::{DependencyMap, DependencySupplier},
+    },
+    error_handlers::LoggingErrorHandler,
+    types::Update,
+    Bot,
+};
+use tokio::sync::{Barrier, Notify};
+use warp::Filter;
+
+type Errors<E = Infallible> = Arc<Mutex<Vec<(i32, UpdateError<E>)>>>;
+
+#[tokio::test]
+async fn updates_from_one_chat_are_handled_in_order() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let handled = Arc::clone(&handled);
+        dptree::endpoint(move |upd: Update| {
+            let handled = Arc::clone(&handled);
+            async move {
+                // Make earlier updates slower, so that concurrent handling would
+                // reorder them.
+                tokio::time::sleep(Duration::from_millis(20 - upd.id as u64)).await;
+                handled.lock().unwrap().push(upd.id);
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler).build();
+    dispatch(&mut dispatcher, (1..=10).map(|id| message(id, 1))).await;
+
+    assert_eq!(*handled.lock().unwrap(), (1..=10).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn updates_from_different_chats_are_handled_concurrently() {
+    // Each handler waits for the other one, so sequential handling would never
+    // finish.
+    let barrier = Arc::new(Barrier::new(2));
+    let handler = dptree::endpoint(move |_: Update| {
+        let barrier = Arc::clone(&barrier);
+        async move {
+            barrier.wait().await;
+            Ok::<_, Infallible>(())
+        }
+    });
+
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler).build();
+    let dispatching = dispatch(&mut dispatcher, vec![message(1, 1), message(2, 2)]);
+
+    tokio::time::timeout(Duration::from_secs(5), dispatching)
+        .await
+        .expect("updates from different chats were not handled concurrently");
+}
+
+#[tokio::test]
+async fn slow_chats_dont_block_other_chats() {
+    // The first update from the chat #1 is handled only after the update from
+    // the chat #2, which is received after the queue of the chat #1 is full.
+    let chat_2_handled = Arc::new(Notify::new());
+    let handler = {
+        let chat_2_handled = Arc::clone(&chat_2_handled);
+        dptree::endpoint(move |upd: Update| {
+            let chat_2_handled = Arc::clone(&chat_2_handled);
+            async move {
+                match upd.id {
+                    1 => chat_2_handled.notified().await,
+                    4 => chat_2_handled.notify_one(),
+                    _ => {}
+                }
+
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler).worker_queue_size(1).build();
+    let updates = vec![message(1, 1), message(2, 1), message(3, 1), message(4, 2)];
+
+    tokio::time::timeout(Duration::from_secs(5), dispatch(&mut dispatcher, updates))
+        .await
+        .expect("a slow chat blocked the dispatcher");
+}
+
+#[tokio::test]
+async fn panics_are_reported() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let handled = Arc::clone(&handled);
+        dptree::endpoint(move |upd: Update| {
+            let handled = Arc::clone(&handled);
+            async move {
+                if upd.id == 1 {
+                    panic!("handler panicked");
+                }
+
+                handled.lock().unwrap().push(upd.id);
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let errors = Errors::default();
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler)
+        .update_error_handler(collect_errors(&errors))
+        .build();
+    dispatch(&mut dispatcher, vec![message(1, 1), message(2, 1)]).await;
+
+    let errors = errors.lock().unwrap();
+    assert!(
+        matches!(&errors[..], [(1, UpdateError::Panic(message))] if message == "handler panicked"),
+        "{:?}",
+        errors
+    );
+    // The worker of the chat survives the panic.
+    assert_eq!(*handled.lock().unwrap(), vec![2]);
+}
+
+#[tokio::test]
+async fn timeouts_are_reported() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let handled = Arc::clone(&handled);
+        dptree::endpoint(move |upd: Update| {
+            let handled = Arc::clone(&handled);
+            async move {
+                if upd.id == 1 {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+
+                handled.lock().unwrap().push(upd.id);
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let errors = Errors::default();
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler)
+        .update_error_handler(collect_errors(&errors))
+        .handler_timeout(Duration::from_millis(50))
+        .build();
+    dispatch(&mut dispatcher, vec![message(1, 1), message(2, 1)]).await;
+
+    let errors = errors.lock().unwrap();
+    assert!(
+        matches!(
+            &errors[..],
+            [(1, UpdateError::Timeout(duration))] if *duration == Duration::from_millis(50)
+        ),
+        "{:?}",
+        errors
+    );
+    assert_eq!(*handled.lock().unwrap(), vec![2]);
+}
+
+#[tokio::test]
+async fn branch_timeouts_abort_only_their_branch() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = dptree::entry()
+        .branch(
+            dptree::filter(|upd: Update| upd.id == 1)
+                .chain(dptree::endpoint(|| async {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                }))
+                .timeout(Duration::from_millis(50), || Err("timed out")),
+        )
+        .branch({
+            let handled = Arc::clone(&handled);
+            dptree::endpoint(move |upd: Update| {
+                let handled = Arc::clone(&handled);
+                async move {
+                    // Slower than the timeout of the first branch, which doesn't apply here.
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    handled.lock().unwrap().push(upd.id);
+                    Ok(())
+                }
+            })
+        });
+
+    let errors = Errors::default();
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler)
+        .update_error_handler(collect_errors(&errors))
+        .build();
+    dispatch(&mut dispatcher, vec![message(1, 1), message(2, 2)]).await;
+
+    let errors = errors.lock().unwrap();
+    assert!(matches!(&errors[..], [(1, UpdateError::Handler("timed out"))]), "{:?}", errors);
+    assert_eq!(*handled.lock().unwrap(), vec![2]);
+}
+
+#[tokio::test]
+async fn middlewares_can_stop_handling() {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handler = {
+        let handled = Arc::clone(&handled);
+        dptree::endpoint(move |upd: Update| {
+            let handled = Arc::clone(&handled);
+            async move {
+                handled.lock().unwrap().push(upd.id);
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let unhandled = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler)
+        .middleware(|deps: DependencyMap, next: Next<Infallible>| async move {
+            let upd: Arc<Update> = deps.get();
+            if upd.id % 2 == 0 {
+                return ControlFlow::Break(Ok(()));
+            }
+
+            next.run(deps).await
+        })
+        .default_handler({
+            let unhandled = Arc::clone(&unhandled);
+            move |upd| {
+                unhandled.lock().unwrap().push(upd.id);
+                async {}
+            }
+        })
+        .build();
+    dispatch(&mut dispatcher, (1..=4).map(|id| message(id, 1))).await;
+
+    assert_eq!(*handled.lock().unwrap(), vec![1, 3]);
+    assert!(unhandled.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn shutdown_aborts_handlers_after_timeout() {
+    let started = Arc::new(Notify::new());
+    let finished = Arc::new(Mutex::new(false));
+    let handler = {
+        let started = Arc::clone(&started);
+        let finished = Arc::clone(&finished);
+        dptree::endpoint(move |_: Update| {
+            let started = Arc::clone(&started);
+            let finished = Arc::clone(&finished);
+            async move {
+                started.notify_one();
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                *finished.lock().unwrap() = true;
+                Ok::<_, Infallible>(())
+            }
+        })
+    };
+
+    let errors = Errors::default();
+    let mut dispatcher = Dispatcher::builder(mock_bot(), handler)
+        .update_error_handler(collect_errors(&errors))
+        .shutdown_timeout(Duration::from_millis(100))
+        .build();
+    let token = dispatcher.shutdown_token();
+
+    // The sender is kept alive, so the listener doesn't end by itself.
+    let (tx, listener) = channel(16);
+    send(&tx, vec![message(1, 1), message(2, 1)]).await;
+
+    let dispatching = async {
+        tokio::join!(
+            dispatcher.dispatch_with_listener(listener, LoggingErrorHandler::new()),
+            async {
+                started.notified().await;
+                token.shutdown().expect("the dispatcher is running").await;
+            },
+        );
+    };
+
+    tokio::time::timeout(Duration::from_secs(10), dispatching)
+        .await
+        .expect("the dispatcher didn't abort the handler on shutdown");
+    assert!(!*finished.lock().unwrap());
+    drop(tx);
+
+    // Both the running handler and the update waiting for it are reported.
+    let errors = errors.lock().unwrap();
+    assert!(
+        matches!(&errors[..], [(1, UpdateError::Aborted), (2, UpdateError::Aborted)]),
+        "{:?}",
+        errors
+    );
+}
+
+/// Starts a mock Telegram Bot API server and returns a bot which uses it.
+///
+/// The server responds to any request with [`Me`], which is the only request
+/// made by the dispatcher itself.
+///
+/// [`Me`]: teloxide::types::Me
+fn mock_bot() -> Bot {
+    let me = serde_json::json!({
+        "ok": true,
+        "result": {
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": "bot",
+            "can_join_groups": false,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false
+        }
+    });
+
+    let routes = warp::any().map(move || warp::reply::json(&me));
+    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    Bot::new("TOKEN").set_api_url(format!("http://{}/", addr).parse().unwrap())
+}
+
+/// Sends `updates` to the dispatcher via [`channel`] and waits until all of
+/// them are handled.
+async fn dispatch<E, I>(dispatcher: &mut Dispatcher<Bot, E>, updates: I)
+where
+    E: Send + Sync + 'static,
+    I: IntoIterator<Item = Update>,
+{
+    let (tx, listener) = channel(16);
+    let sending = async move { send(&tx, updates).await };
+
+    // The listener ends once `tx` is dropped.
+    tokio::join!(dispatcher.dispatch_with_listener(listener, LoggingErrorHandler::new()), sending);
+}
+
+async fn send<I>(tx: &UpdateSender, updates: I)
+where
+    I: IntoIterator<Item = Update>,
+{
+    for upd in updates {
+        tx.send(upd).await.unwrap();
+    }
+}
+
+fn collect_errors<E>(
+    errors: &Errors<E>,
+) -> Arc<impl Fn(UpdateError<E>, DependencyMap) -> futures::future::Ready<()>>
+where
+    E: Send + 'static,
+{
+    let errors = Arc::clone(errors);
+    Arc::new(move |err: UpdateError<E>, deps: DependencyMap| {
+        let upd: Arc<Update> = deps.get();
+        errors.lock().unwrap().push((upd.id, err));
+        futures::future::ready(())
+    })
+}
+
+fn message(id: i32, chat_id: i64) -> Update {
+    serde_json::from_value(serde_json::json!({
+        "update_id": id,
+        "message": {
+            "message_id": id,
+            "date": 0,
+            "chat": { "id": chat_id, "type": "private", "first_name": "User" },
+            "from": { "id": chat_id, "is_bot": false, "first_name": "User" },
+            "text": "text"
+        }
+    }))
+    .unwrap()
+}
@@ -1,8 +1,22 @@
+use futures::StreamExt;
 use std::{
     fmt::{Debug, Display},
     sync::Arc,
+    time::Duration,
 };
-use teloxide::dispatching::dialogue::{RedisStorage, RedisStorageError, Serializer, Storage};
+use teloxide::{
+    dispatching::{
+        dialogue::{EnumerableStorage, RedisStorage, RedisStorageError, Serializer, Storage},
+        stop_token::StopToken,
+        update_listeners::{
+            redis_stream, AsUpdateStream, RedisStreamOptions, RedisStreamProducer, UpdateListener,
+        },
+    },
+    types::Update,
+};
+
+#[path = "../src/dispatching/update_listeners/test_utils.rs"]
+mod test_utils;
 
 #[tokio::test]
 async fn test_redis_json() {
@@ -76,4 +90,87 @@ where
         Arc::clone(&storage).remove_dialogue(1).await.unwrap_err(),
         RedisStorageError::DialogueNotFound
     ));
+
+    // Check that an expired dialogue is reported only once.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(1, "ABC".to_owned(), Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), Some("ABC".to_owned()));
+    assert!(!Arc::clone(&storage).take_expired(1).await.unwrap());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), None);
+    assert!(Arc::clone(&storage).take_expired(1).await.unwrap());
+    assert!(!Arc::clone(&storage).take_expired(1).await.unwrap());
+
+    // Check that concurrent updates don't overwrite each other. Every update uses
+    // its own connection, so they do conflict.
+    let updates = (0..10).map(|_| {
+        Arc::clone(&storage).update_dialogue_with(1, None, |dialogue: Option<Dialogue>| {
+            dialogue.unwrap_or_default() + "A"
+        })
+    });
+    for res in futures::future::join_all(updates).await {
+        res.unwrap();
+    }
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), Some("A".repeat(10)));
+    Arc::clone(&storage).remove_dialogue(1).await.unwrap();
+
+    // Check that dialogues can be enumerated and removed in bulk.
+    Arc::clone(&storage).update_dialogue(1, "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(11, "DEF".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(256, "DEF".to_owned()).await.unwrap();
+
+    let mut dialogues: Vec<(i64, Dialogue)> =
+        futures::TryStreamExt::try_collect(Arc::clone(&storage).dialogues()).await.unwrap();
+    dialogues.sort();
+    assert_eq!(dialogues, [(1, "ABC".to_owned()), (11, "DEF".to_owned()), (256, "DEF".to_owned())]);
+
+    let removed = EnumerableStorage::<Dialogue, i64>::remove_dialogues_where(
+        Arc::clone(&storage),
+        |_, dialogue| dialogue == "DEF",
+    )
+    .await
+    .unwrap();
+    assert_eq!(removed, 2);
+    assert_eq!(
+        EnumerableStorage::<Dialogue, i64>::count_dialogues(Arc::clone(&storage)).await.unwrap(),
+        1
+    );
+
+    Arc::clone(&storage).remove_dialogue(1).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_redis_stream() {
+    const URL: &str = "redis://127.0.0.1:7777";
+
+    use test_utils::update;
+
+    let stream = format!("teloxide-test-stream-{}", std::process::id());
+    let options = || RedisStreamOptions::new(&stream, "workers", "worker-1");
+
+    let producer = RedisStreamProducer::new(URL, &stream, None).unwrap();
+    producer.send(&update(1)).await.unwrap();
+    producer.send(&update(2)).await.unwrap();
+
+    // Acknowledge only the first update, so the second one is redelivered.
+    let mut listener = redis_stream(URL, options()).unwrap();
+    let acknowledger = listener.acknowledger().unwrap();
+    let mut updates = listener.as_stream();
+
+    let first = updates.next().await.unwrap().unwrap();
+    acknowledger.ack(first.id).await;
+    assert_eq!(first.id, 1);
+    assert_eq!(updates.next().await.unwrap().unwrap().id, 2);
+    drop(updates);
+    listener.stop_token().stop();
+
+    let mut listener = redis_stream(URL, options()).unwrap();
+    let _acknowledger = listener.acknowledger().unwrap();
+    let mut updates = listener.as_stream();
+    assert_eq!(updates.next().await.unwrap().unwrap().id, 2);
 }
@@ -1,8 +1,11 @@
 use std::{
     fmt::{Debug, Display},
     sync::Arc,
+    time::Duration,
+};
+use teloxide::dispatching::dialogue::{
+    ChatUserKey, EnumerableStorage, Serializer, SqliteStorage, SqliteStorageError, Storage,
 };
-use teloxide::dispatching::dialogue::{Serializer, SqliteStorage, SqliteStorageError, Storage};
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_sqlite_json() {
@@ -33,6 +36,95 @@ async fn test_sqlite_cbor() {
     test_sqlite(storage).await;
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_chat_user_keys() {
+    let storage =
+        SqliteStorage::open("./test_db4.sqlite", teloxide::dispatching::dialogue::serializer::Json)
+            .await
+            .unwrap();
+
+    let first = ChatUserKey::new(-100, 1);
+    let second = ChatUserKey::new(-100, 2);
+
+    Arc::clone(&storage).update_dialogue(first, "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(-100_i64, "DEF".to_owned()).await.unwrap();
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(first).await.unwrap(), Some("ABC".to_owned()));
+    assert_eq!(Arc::clone(&storage).get_dialogue(second).await.unwrap(), None::<Dialogue>);
+    assert_eq!(Arc::clone(&storage).get_dialogue(-100_i64).await.unwrap(), Some("DEF".to_owned()));
+
+    Storage::<Dialogue, _>::remove_dialogue(Arc::clone(&storage), first).await.unwrap();
+    Storage::<Dialogue, _>::remove_dialogue(Arc::clone(&storage), -100_i64).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_mixed_dialogues() {
+    let storage =
+        SqliteStorage::open("./test_db5.sqlite", teloxide::dispatching::dialogue::serializer::Json)
+            .await
+            .unwrap();
+
+    Arc::clone(&storage).update_dialogue(1, "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(2, vec![1, 2, 3]).await.unwrap();
+
+    // Dialogues of another type are skipped instead of failing bulk operations.
+    let dialogues: Vec<(i64, Dialogue)> =
+        futures::TryStreamExt::try_collect(Arc::clone(&storage).dialogues()).await.unwrap();
+    assert_eq!(dialogues, [(1, "ABC".to_owned())]);
+    assert_eq!(
+        EnumerableStorage::<Dialogue, i64>::count_dialogues(Arc::clone(&storage)).await.unwrap(),
+        1
+    );
+
+    let removed =
+        EnumerableStorage::<Dialogue, i64>::remove_dialogues_where(Arc::clone(&storage), |_, _| {
+            true
+        })
+        .await
+        .unwrap();
+    assert_eq!(removed, 1);
+    assert_eq!(Arc::clone(&storage).get_dialogue(2).await.unwrap(), Some(vec![1, 2, 3]));
+
+    Storage::<Vec<i32>, _>::remove_dialogue(Arc::clone(&storage), 2_i64).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_sqlite_text_keys() {
+    // A table created by an older version of teloxide.
+    let pool = sqlx::SqlitePool::connect("sqlite:./test_db6.sqlite?mode=rwc").await.unwrap();
+    sqlx::query("DROP TABLE IF EXISTS teloxide_dialogues").execute(&pool).await.unwrap();
+    sqlx::query(
+        "CREATE TABLE teloxide_dialogues (chat_id BIGINT PRIMARY KEY, dialogue BLOB NOT NULL)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO teloxide_dialogues VALUES (7, ?)")
+        .bind(b"\"ABC\"".to_vec())
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    let storage =
+        SqliteStorage::open("./test_db6.sqlite", teloxide::dispatching::dialogue::serializer::Json)
+            .await
+            .unwrap();
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(7).await.unwrap(), Some("ABC".to_owned()));
+
+    // Keys which look like numbers are not coerced.
+    Arc::clone(&storage).update_dialogue("007".to_owned(), "DEF".to_owned()).await.unwrap();
+    assert_eq!(Arc::clone(&storage).get_dialogue(7).await.unwrap(), Some("ABC".to_owned()));
+    assert_eq!(
+        Arc::clone(&storage).get_dialogue("007".to_owned()).await.unwrap(),
+        Some("DEF".to_owned())
+    );
+
+    Storage::<Dialogue, _>::remove_dialogue(Arc::clone(&storage), 7_i64).await.unwrap();
+    Storage::<Dialogue, _>::remove_dialogue(Arc::clone(&storage), "007".to_owned()).await.unwrap();
+}
+
 type Dialogue = String;
 
 macro_rules! test_dialogues {
@@ -72,4 +164,59 @@ where
         Arc::clone(&storage).remove_dialogue(1).await.unwrap_err(),
         SqliteStorageError::DialogueNotFound
     ));
+
+    // Check that an expired dialogue is reported only once.
+    Arc::clone(&storage)
+        .update_dialogue_with_ttl(1, "ABC".to_owned(), Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), Some("ABC".to_owned()));
+    assert!(!Arc::clone(&storage).take_expired(1).await.unwrap());
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), None);
+    assert!(matches!(
+        Arc::clone(&storage).remove_dialogue(1).await.unwrap_err(),
+        SqliteStorageError::DialogueNotFound
+    ));
+    assert!(Arc::clone(&storage).take_expired(1).await.unwrap());
+    assert!(!Arc::clone(&storage).take_expired(1).await.unwrap());
+
+    // Check that concurrent updates don't overwrite each other.
+    let updates = (0..10).map(|_| {
+        Arc::clone(&storage).update_dialogue_with(1, None, |dialogue: Option<Dialogue>| {
+            dialogue.unwrap_or_default() + "A"
+        })
+    });
+    for res in futures::future::join_all(updates).await {
+        res.unwrap();
+    }
+
+    assert_eq!(Arc::clone(&storage).get_dialogue(1).await.unwrap(), Some("A".repeat(10)));
+    Arc::clone(&storage).remove_dialogue(1).await.unwrap();
+
+    // Check that dialogues can be enumerated and removed in bulk.
+    Arc::clone(&storage).update_dialogue(1, "ABC".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(11, "DEF".to_owned()).await.unwrap();
+    Arc::clone(&storage).update_dialogue(256, "DEF".to_owned()).await.unwrap();
+
+    let mut dialogues: Vec<(i64, Dialogue)> =
+        futures::TryStreamExt::try_collect(Arc::clone(&storage).dialogues()).await.unwrap();
+    dialogues.sort();
+    assert_eq!(dialogues, [(1, "ABC".to_owned()), (11, "DEF".to_owned()), (256, "DEF".to_owned())]);
+
+    let removed = EnumerableStorage::<Dialogue, i64>::remove_dialogues_where(
+        Arc::clone(&storage),
+        |_, dialogue| dialogue == "DEF",
+    )
+    .await
+    .unwrap();
+    assert_eq!(removed, 2);
+    assert_eq!(
+        EnumerableStorage::<Dialogue, i64>::count_dialogues(Arc::clone(&storage)).await.unwrap(),
+        1
+    );
+
+    Arc::clone(&storage).remove_dialogue(1).await.unwrap();
 }